@@ -0,0 +1,135 @@
+use serde_json::Value;
+use worker::Env;
+
+use crate::{claims::ClaimsRequest, clients::ClientSecret, consts::{get_var, is_workspace_domain, ALLOW_EXTERNAL_ACCOUNTS}, google::GoogleIdToken, handler_error::HandlerError, users::is_enforced_in_2sv};
+
+/// `acr` of a login by an account that has to use a second factor
+/// Source: https://openid.net/specs/openid-provider-authentication-policy-extension-1_0.html#rfc.section.4
+pub const ACR_MULTI_FACTOR: &str = "http://schemas.openid.net/pape/policies/2007/06/multi-factor";
+
+/// How the user authenticated with Google
+pub struct Authentication {
+  /// Source: https://www.rfc-editor.org/rfc/rfc8176#section-2
+  pub amr: Vec<String>,
+  pub acr: Option<String>
+}
+
+/// Work out how the user authenticated.
+///
+/// Google's `amr` and `acr` are relayed when it sets them, which is
+/// rare. Otherwise, when the client requires MFA or asked for
+/// `acr`/`amr`, they come from whether the workspace enforces 2-Step
+/// Verification for the account: `mfa` and `ACR_MULTI_FACTOR` if it
+/// does. That's an account policy rather than a record of this login,
+/// since users can skip their second factor on trusted devices.
+pub async fn authenticate(
+  env: &Env,
+  client: &ClientSecret,
+  acr_values: &[String],
+  claims: Option<&ClaimsRequest>,
+  google_token: &GoogleIdToken
+) -> Result<Authentication, HandlerError> {
+  if google_token.amr.is_some() || google_token.acr.is_some() {
+    return Ok(Authentication {
+      amr: google_token.amr.clone().unwrap_or_default(),
+      acr: google_token.acr.clone()
+    })
+  }
+
+  let requested = !acr_values.is_empty() || claims.is_some_and(
+    |claims| claims.id_token.contains_key("acr") || claims.id_token.contains_key("amr")
+  );
+
+  if !(client.require_mfa || requested)
+    || !is_enforced_in_2sv(env, &google_token.user_email).await?
+  {
+    return Ok(Authentication { amr: Vec::new(), acr: None })
+  }
+
+  Ok(Authentication {
+    amr: vec!["mfa".into()],
+    acr: Some(ACR_MULTI_FACTOR.into())
+  })
+}
+
+/// Why the user may not get tokens for `client`, if they may not.
+/// Checked at `/callback`, so the client gets `access_denied`, then
+/// again when the code is redeemed and on every refresh, so access
+/// ends once the account no longer qualifies.
+pub fn authentication_denial(
+  env: &Env,
+  client: &ClientSecret,
+  acr_values: &[String],
+  claims: Option<&ClaimsRequest>,
+  google_token: &GoogleIdToken,
+  Authentication { amr, acr }: &Authentication
+) -> Result<Option<&'static str>, HandlerError> {
+  if let Some(reason) = account_denial(env, google_token)? {
+    return Ok(Some(reason))
+  }
+
+  if client.require_mfa && !amr.iter().any(|method| method == "mfa") {
+    return Ok(Some("Multi-factor authentication is required"))
+  }
+
+  if unsatisfied_acr(claims, acr_values, acr.as_deref()) {
+    return Ok(Some("The requested acr can't be satisfied"))
+  }
+
+  Ok(None)
+}
+
+/// Why the Google account may not log in, if it may not. Only
+/// verified emails are accepted, and only accounts of
+/// `GOOGLE_WORKSPACE_DOMAIN` unless `ALLOW_EXTERNAL_ACCOUNTS` is set.
+/// Source: https://developers.google.com/identity/openid-connect/openid-connect#an-id-tokens-payload
+fn account_denial(
+  env: &Env,
+  google_token: &GoogleIdToken
+) -> Result<Option<&'static str>, HandlerError> {
+  if google_token.email_verified != Some(true) {
+    return Ok(Some("The account's email address isn't verified"))
+  }
+
+  if get_var(env, ALLOW_EXTERNAL_ACCOUNTS, false)? {
+    return Ok(None)
+  }
+
+  // `hd` is the only reliable sign of a workspace account, an email
+  // address can be at the workspace domain without belonging to it
+  let workspace_account = google_token.hosted_domain.as_deref()
+    .is_some_and(|hd| is_workspace_domain(env, hd));
+
+  if !workspace_account {
+    return Ok(Some("The account doesn't belong to the workspace"))
+  }
+
+  Ok(None)
+}
+
+/// The `acr` isn't one of the `acr_values`, or of the values of an
+/// essential `acr` claim request. Both are enforced, since clients use
+/// them to require MFA. Voluntary claim requests only affect the
+/// returned claims.
+/// Source: https://openid.net/specs/openid-connect-core-1_0.html#acrSemantics
+fn unsatisfied_acr(
+  claims: Option<&ClaimsRequest>,
+  acr_values: &[String],
+  acr: Option<&str>
+) -> bool {
+  let essential = match claims.and_then(|claims| claims.id_token.get("acr")) {
+    Some(Some(request)) if request.essential == Some(true) => request.value.iter()
+      .chain(request.values.iter().flatten())
+      .cloned()
+      .collect(),
+    _ => Vec::new()
+  };
+
+  let values = acr_values.iter()
+    .map(|value| Value::from(value.as_str()))
+    .chain(essential)
+    .collect::<Vec<_>>();
+
+  !values.is_empty()
+    && !acr.is_some_and(|acr| values.contains(&Value::from(acr)))
+}
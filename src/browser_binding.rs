@@ -0,0 +1,92 @@
+use axum::http::{header, HeaderMap};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::Duration;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use worker::Env;
+
+use crate::{consts::{get_secret, Secret}, handler_error::HandlerError, oidc_token::new_token};
+
+// `__Host-` forces `Secure`, `Path=/` and no `Domain` attribute.
+// Source: https://www.rfc-editor.org/rfc/rfc6265bis#name-the-__host-prefix
+const COOKIE_NAME: &str = "__Host-authorize_binding";
+
+/// Same lifetime as the stored `AuthorizeState`
+const COOKIE_TTL: Duration = Duration::minutes(10);
+
+pub struct BrowserBinding {
+  /// random value stored in `AuthorizeState`
+  pub binding: String,
+  /// `Set-Cookie` header value carrying the signed binding
+  pub set_cookie: String
+}
+
+/// Create a random binding value and a signed, short-lived cookie
+/// holding it. The cookie ties the `/callback` request to the
+/// browser that started the flow at `/authorize`.
+pub fn new_browser_binding(env: &Env) -> BrowserBinding {
+  let binding = new_token::<16>();
+
+  let set_cookie = format!(
+    "{COOKIE_NAME}={binding}.{signature}; Max-Age={max_age}; Path=/; Secure; HttpOnly; SameSite=Lax",
+    signature = URL_SAFE_NO_PAD.encode(
+      new_mac(env, &binding).finalize().into_bytes()
+    ),
+    max_age = COOKIE_TTL.num_seconds()
+  );
+
+  BrowserBinding { binding, set_cookie }
+}
+
+/// `Set-Cookie` header value which removes the binding cookie
+pub fn clear_browser_binding() -> String {
+  format!("{COOKIE_NAME}=; Max-Age=0; Path=/; Secure; HttpOnly; SameSite=Lax")
+}
+
+/// Verify that the request carries a validly signed binding cookie
+/// matching the one stored alongside the authorize state.
+pub fn verify_browser_binding(
+  env: &Env,
+  headers: &HeaderMap,
+  stored_binding: &str
+) -> Result<(), HandlerError> {
+  let Some((binding, signature)) = find_cookie(headers)
+    .and_then(|value| value.split_once('.'))
+    else {
+      return Err(HandlerError::BrowserBinding)
+    };
+
+  let Ok(signature) = URL_SAFE_NO_PAD.decode(signature) else {
+    return Err(HandlerError::BrowserBinding)
+  };
+
+  // constant time comparison
+  new_mac(env, binding)
+    .verify_slice(&signature)
+    .map_err(|_| HandlerError::BrowserBinding)?;
+
+  if binding != stored_binding {
+    return Err(HandlerError::BrowserBinding)
+  }
+
+  Ok(())
+}
+
+fn find_cookie(headers: &HeaderMap) -> Option<&str> {
+  headers.get_all(header::COOKIE)
+    .iter()
+    .filter_map(|value| value.to_str().ok())
+    .flat_map(|value| value.split(';'))
+    .filter_map(|cookie| cookie.trim().split_once('='))
+    .find_map(|(name, value)| (name == COOKIE_NAME).then_some(value))
+}
+
+fn new_mac(env: &Env, binding: &str) -> Hmac<Sha256> {
+  let mut mac = Hmac::<Sha256>::new_from_slice(
+    get_secret(env, Secret::COOKIE_SECRET).as_bytes()
+  )
+    // HMAC accepts keys of any length
+    .unwrap();
+  mac.update(binding.as_bytes());
+  mac
+}
@@ -0,0 +1,204 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use openidconnect::SubjectIdentifier;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use worker::Env;
+
+use crate::{clients::ClientSecret, google::GoogleIdToken, handler_error::HandlerError, scope::ScopeRegistry, state::CommonTokenState, subject::client_subject};
+
+// ---------- CLAIMS REQUEST ----------
+
+/// The `claims` authorization request parameter. Claims named here are
+/// returned in addition to the ones granted by scopes.
+/// Source: https://openid.net/specs/openid-connect-core-1_0.html#ClaimsParameter
+#[derive(Serialize, Deserialize)]
+pub struct ClaimsRequest {
+  #[serde(default)]
+  pub userinfo: BTreeMap<String, Option<IndividualClaimRequest>>,
+  #[serde(default)]
+  pub id_token: BTreeMap<String, Option<IndividualClaimRequest>>
+}
+
+/// Source: https://openid.net/specs/openid-connect-core-1_0.html#IndividualClaimsRequests
+#[derive(Serialize, Deserialize)]
+pub struct IndividualClaimRequest {
+  /// Only an essential `acr` changes the outcome of a login. Other
+  /// claims are returned whenever they're available.
+  pub essential: Option<bool>,
+  pub value: Option<Value>,
+  pub values: Option<Vec<Value>>
+}
+
+// ---------- CLAIM REGISTRY ----------
+
+/// Everything claim values are computed from
+pub struct ClaimSources<'a> {
+  pub google_token: &'a GoogleIdToken,
+  /// `None` unless a released claim needs them
+  pub groups: Option<&'a Vec<String>>,
+  pub roles: Option<&'a Vec<String>>,
+  /// The client's `directory_claims`, released with every other claim
+  pub directory: &'a Map<String, Value>
+}
+
+struct ClaimDefinition {
+  name: &'static str,
+  /// Google only includes the claim in its ID token if this scope is
+  /// requested from it
+  google_scope: Option<&'static str>,
+  value: fn(&ClaimSources) -> Option<Value>
+}
+
+/// Every user claim the worker can release. Scopes map to these names
+/// in `scope::scope_registry`.
+const CLAIMS: [ClaimDefinition; 10] = [
+  ClaimDefinition {
+    name: "groups",
+    google_scope: None,
+    value: |s| s.groups.map(|groups| groups.as_slice().into())
+  },
+  ClaimDefinition {
+    name: "roles",
+    google_scope: None,
+    value: |s| s.roles.map(|roles| roles.as_slice().into())
+  },
+  // Google's "email" scope is always requested
+  ClaimDefinition {
+    name: "email",
+    google_scope: None,
+    value: |s| Some(s.google_token.user_email.as_str().into())
+  },
+  ClaimDefinition {
+    name: "email_verified",
+    google_scope: None,
+    value: |s| s.google_token.email_verified.map(Value::from)
+  },
+  ClaimDefinition {
+    name: "hd",
+    google_scope: None,
+    value: |s| s.google_token.hosted_domain.as_deref().map(Value::from)
+  },
+  ClaimDefinition {
+    name: "name",
+    google_scope: Some("profile"),
+    value: |s| s.google_token.name.as_deref().map(Value::from)
+  },
+  ClaimDefinition {
+    name: "given_name",
+    google_scope: Some("profile"),
+    value: |s| s.google_token.given_name.as_deref().map(Value::from)
+  },
+  ClaimDefinition {
+    name: "family_name",
+    google_scope: Some("profile"),
+    value: |s| s.google_token.family_name.as_deref().map(Value::from)
+  },
+  ClaimDefinition {
+    name: "picture",
+    google_scope: Some("profile"),
+    value: |s| s.google_token.picture.as_deref().map(Value::from)
+  },
+  ClaimDefinition {
+    name: "locale",
+    google_scope: Some("profile"),
+    value: |s| s.google_token.locale.as_deref().map(Value::from)
+  }
+];
+
+/// Names of every user claim the worker can release
+pub fn claim_names() -> impl Iterator<Item = &'static str> {
+  CLAIMS.iter().map(|claim| claim.name)
+}
+
+/// Claims the ID token sets itself
+const TOKEN_CLAIMS: [&str; 12] = [
+  "iss", "sub", "aud", "azp", "exp", "iat", "auth_time", "acr", "amr",
+  "jti", "nonce", "at_hash"
+];
+
+/// Whether `name` is a claim of the token itself or one of `CLAIMS`
+pub fn is_reserved_claim(name: &str) -> bool {
+  TOKEN_CLAIMS.contains(&name) || claim_names().any(|claim| claim == name)
+}
+
+/// Claims released to `target` by the granted `scopes` and the claims
+/// request, or to either target if `target` is `None`.
+pub fn released_claims<'a>(
+  registry: &'a ScopeRegistry,
+  scopes: impl IntoIterator<Item = &'a str>,
+  claims: Option<&'a ClaimsRequest>,
+  target: Option<ClaimTarget>
+) -> BTreeSet<&'a str> {
+  let by_scope = scopes.into_iter()
+    .filter_map(|scope| registry.get(scope))
+    .flatten()
+    .map(String::as_str);
+
+  let by_request = claims.into_iter().flat_map(|claims| {
+    let id_token = matches!(target, None | Some(ClaimTarget::IdToken))
+      .then_some(&claims.id_token);
+    let userinfo = matches!(target, None | Some(ClaimTarget::Userinfo))
+      .then_some(&claims.userinfo);
+
+    id_token.into_iter()
+      .chain(userinfo)
+      .flat_map(|requested| requested.keys().map(String::as_str))
+  });
+
+  by_scope.chain(by_request).collect()
+}
+
+/// Scopes to request from Google so its ID token carries the
+/// `released` claims
+pub fn google_scopes(released: &BTreeSet<&str>) -> BTreeSet<&'static str> {
+  CLAIMS.iter()
+    .filter(|claim| released.contains(claim.name))
+    .filter_map(|claim| claim.google_scope)
+    .collect()
+}
+
+// ---------- USER CLAIMS ----------
+
+/// Where claims are returned to the client
+#[derive(Clone, Copy)]
+pub enum ClaimTarget {
+  IdToken,
+  Userinfo
+}
+
+/// Claims about the user, shared by the ID token and `/userinfo`
+/// Source: https://openid.net/specs/openid-connect-core-1_0.html#StandardClaims
+#[derive(Serialize, Deserialize)]
+pub struct UserClaims {
+  pub sub: SubjectIdentifier,
+  #[serde(flatten)]
+  pub claims: Map<String, Value>
+}
+
+/// Compute the claims released to `target`. Claims without a value,
+/// like `picture` for a user without one, are left out.
+pub async fn user_claims(
+  env: &Env,
+  client: &ClientSecret,
+  common: &CommonTokenState,
+  registry: &ScopeRegistry,
+  sources: &ClaimSources<'_>,
+  target: ClaimTarget
+) -> Result<UserClaims, HandlerError> {
+  let released = released_claims(
+    registry,
+    common.scopes.iter().map(String::as_str),
+    common.claims.as_ref(),
+    Some(target)
+  );
+
+  Ok(UserClaims {
+    sub: client_subject(env, client, &sources.google_token.subject).await?,
+    claims: CLAIMS.iter()
+      .filter(|claim| released.contains(claim.name))
+      .filter_map(|claim| Some((claim.name.to_string(), (claim.value)(sources)?)))
+      .chain(sources.directory.clone())
+      .collect()
+  })
+}
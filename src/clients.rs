@@ -0,0 +1,264 @@
+use std::collections::BTreeMap;
+
+use chrono::Duration;
+use serde::{de::Error, Deserialize, Deserializer};
+use url::Url;
+use worker::Env;
+
+use crate::{claims::is_reserved_claim, consts::{get_secret, get_var, Secret, ACCESS_TOKEN_LIFETIME, ID_TOKEN_LIFETIME}, handler_error::HandlerError, jwe::{ClientJwks, ContentEncryption, KeyManagementAlgorithm}, signing_keys::SigningAlgorithm, subject::SubjectType};
+
+/// A registered client, keyed by its client_id in `CLIENT_SECRETS`
+#[derive(Deserialize)]
+pub struct ClientSecret {
+  pub redirect_uris: Vec<Url>,
+  /// Overrides `SIGNING_ALGORITHM` for this client's ID tokens
+  /// Source: https://openid.net/specs/openid-connect-registration-1_0.html#ClientMetadata
+  pub id_token_signed_response_alg: Option<SigningAlgorithm>,
+  /// Seconds. Overrides `ID_TOKEN_LIFETIME` for this client.
+  pub id_token_lifetime: Option<u32>,
+  /// Seconds. Overrides `ACCESS_TOKEN_LIFETIME` for this client.
+  pub access_token_lifetime: Option<u32>,
+  #[serde(default)]
+  pub subject_type: SubjectType,
+  /// Pairwise clients whose redirect_uris span several hosts use this
+  /// host as their sector identifier instead.
+  pub sector_identifier_uri: Option<Url>,
+  /// Encrypt this client's ID tokens after signing them
+  /// Source: https://openid.net/specs/openid-connect-registration-1_0.html#ClientMetadata
+  pub id_token_encrypted_response_alg: Option<KeyManagementAlgorithm>,
+  /// Defaults to A128CBC-HS256 when only the alg is set
+  pub id_token_encrypted_response_enc: Option<ContentEncryption>,
+  /// The client's public keys, ID tokens are encrypted to one of them
+  pub jwks: Option<ClientJwks>,
+  /// Deny logins without 2-Step Verification
+  #[serde(default)]
+  pub require_mfa: bool,
+  /// APIs the client may request audience restricted access tokens for
+  /// Source: https://www.rfc-editor.org/rfc/rfc8707
+  #[serde(default)]
+  pub resources: Vec<Url>,
+  /// Patterns of the groups this client is given, where `*` matches
+  /// any characters. Empty gives every group.
+  #[serde(default)]
+  pub groups: Vec<String>,
+  /// Only users in a group whose email matches one of these patterns
+  /// may log in. Empty allows every user.
+  #[serde(default)]
+  pub allowed_groups: Vec<String>,
+  /// Users in a group whose email matches one of these patterns may
+  /// not log in, even if they're in an allowed group
+  #[serde(default)]
+  pub denied_groups: Vec<String>,
+  /// Claims to add from the user's Directory API resource, mapped to a
+  /// JSON Pointer into it, e.g. `"/organizations/0/department"`
+  /// Source: https://developers.google.com/admin-sdk/directory/reference/rest/v1/users#User
+  #[serde(default, deserialize_with = "deserialize_directory_claims")]
+  pub directory_claims: BTreeMap<String, String>
+}
+
+/// Directory claims can't replace the token's own or built in claims,
+/// and the empty pointer would release the whole Directory API resource
+fn deserialize_directory_claims<'de, D: Deserializer<'de>>(
+  deserializer: D
+) -> Result<BTreeMap<String, String>, D::Error> {
+  let claims = BTreeMap::<String, String>::deserialize(deserializer)?;
+
+  for (claim, pointer) in &claims {
+    if is_reserved_claim(claim) {
+      return Err(D::Error::custom(format!(r#"directory_claims can't set the "{claim}" claim"#)))
+    }
+
+    if !pointer.starts_with('/') {
+      return Err(D::Error::custom(format!(r#"directory_claims pointer "{pointer}" doesn't start with "/""#)))
+    }
+  }
+
+  Ok(claims)
+}
+
+impl ClientSecret {
+  pub fn id_token_lifetime(&self, env: &Env) -> Result<Duration, HandlerError> {
+    lifetime(env, self.id_token_lifetime, ID_TOKEN_LIFETIME)
+  }
+
+  pub fn access_token_lifetime(&self, env: &Env) -> Result<Duration, HandlerError> {
+    lifetime(env, self.access_token_lifetime, ACCESS_TOKEN_LIFETIME)
+  }
+
+  /// Whether `group` matches one of the client's `groups` patterns
+  pub fn includes_group(&self, group: &str) -> bool {
+    self.groups.is_empty()
+      || self.groups.iter().any(|pattern| glob_match(pattern, group))
+  }
+
+  /// Whether logins depend on the user's groups
+  pub fn has_group_policy(&self) -> bool {
+    !self.allowed_groups.is_empty() || !self.denied_groups.is_empty()
+  }
+
+  /// Whether a user in the groups with `group_emails` may log in to
+  /// this client. Checked against the emails rather than the mapped
+  /// groups, which can leave groups out.
+  pub fn allows_groups(&self, group_emails: &[String]) -> bool {
+    let matches = |patterns: &[String]| group_emails.iter()
+      .any(|email| patterns.iter().any(|pattern| glob_match(pattern, email)));
+
+    !matches(&self.denied_groups)
+      && (self.allowed_groups.is_empty() || matches(&self.allowed_groups))
+  }
+}
+
+/// Match `value` against `pattern`, where `*` matches any characters
+pub fn glob_match(pattern: &str, value: &str) -> bool {
+  let mut parts = pattern.split('*');
+
+  // no `*` has to match exactly
+  let first = parts.next().unwrap_or_default();
+  let Some(mut rest) = value.strip_prefix(first) else {
+    return false
+  };
+
+  let Some(last) = parts.next_back() else {
+    return rest.is_empty()
+  };
+
+  // match the parts between `*`s as early as possible
+  for part in parts {
+    match rest.find(part) {
+      Some(index) => rest = &rest[index + part.len()..],
+      None => return false
+    }
+  }
+
+  rest.ends_with(last)
+}
+
+/// Per client lifetime, falling back to the global var, then one hour
+fn lifetime(
+  env: &Env,
+  client_seconds: Option<u32>,
+  var: &'static str
+) -> Result<Duration, HandlerError> {
+  let seconds = match client_seconds {
+    Some(seconds) => seconds,
+    None => get_var(env, var, 3600)?
+  };
+
+  Ok(Duration::seconds(seconds.into()))
+}
+
+// TODO: support loading client ids and redirects in a more generic way
+/// Parse every registered client from the `CLIENT_SECRETS` secret
+pub fn get_clients(env: &Env) -> Result<BTreeMap<String, ClientSecret>, HandlerError> {
+  Ok(serde_json::from_str(get_secret(env, Secret::CLIENT_SECRETS))?)
+}
+
+/// Look up a single registered client
+pub fn get_client(
+  env: &Env,
+  client_id: &str
+) -> Result<Option<ClientSecret>, HandlerError> {
+  Ok(get_clients(env)?.remove(client_id))
+}
+
+#[cfg(test)]
+mod tests {
+  use serde_json::json;
+
+  use super::*;
+
+  fn client(allowed_groups: &[&str], denied_groups: &[&str]) -> ClientSecret {
+    serde_json::from_value(json!({
+      "redirect_uris": ["https://client.example.com/callback"],
+      "allowed_groups": allowed_groups,
+      "denied_groups": denied_groups
+    })).unwrap()
+  }
+
+  fn emails(emails: &[&str]) -> Vec<String> {
+    emails.iter().map(|e| e.to_string()).collect()
+  }
+
+  #[test]
+  fn glob_match_without_star() {
+    assert!(glob_match("admins@example.com", "admins@example.com"));
+    assert!(!glob_match("admins@example.com", "admins@example.com.evil"));
+    assert!(!glob_match("admins@example.com", "sub-admins@example.com"));
+  }
+
+  #[test]
+  fn glob_match_prefix_suffix_and_middle_star() {
+    assert!(glob_match("*@example.com", "admins@example.com"));
+    assert!(!glob_match("*@example.com", "admins@example.org"));
+
+    assert!(glob_match("team-*", "team-ops@example.com"));
+    assert!(!glob_match("team-*", "ops-team@example.com"));
+
+    assert!(glob_match("team-*@example.com", "team-ops@example.com"));
+    assert!(glob_match("team-*@example.com", "team-@example.com"));
+    assert!(!glob_match("team-*@example.com", "team-ops@example.org"));
+
+    assert!(glob_match("*-ops-*", "team-ops-eu@example.com"));
+    assert!(!glob_match("*-ops-*", "team-ops@example.com"));
+  }
+
+  #[test]
+  fn glob_match_empty() {
+    assert!(glob_match("", ""));
+    assert!(!glob_match("", "admins@example.com"));
+    assert!(glob_match("*", ""));
+    assert!(glob_match("*", "admins@example.com"));
+    assert!(glob_match("**", "admins@example.com"));
+  }
+
+  #[test]
+  fn glob_match_overlapping_parts() {
+    // the prefix and suffix can't share characters
+    assert!(!glob_match("a*a", "a"));
+    assert!(glob_match("a*a", "aa"));
+    assert!(!glob_match("ab*bc", "abc"));
+    assert!(glob_match("ab*bc", "abbc"));
+
+    // neither can the middle parts and the suffix
+    assert!(!glob_match("*b*b", "ab"));
+    assert!(glob_match("*b*b", "abb"));
+    assert!(glob_match("*aa*a", "aaaa"));
+    assert!(!glob_match("*aa*a", "aa"));
+  }
+
+  #[test]
+  fn allows_groups_without_policy() {
+    let client = client(&[], &[]);
+
+    assert!(client.allows_groups(&[]));
+    assert!(client.allows_groups(&emails(&["admins@example.com"])));
+  }
+
+  #[test]
+  fn allows_groups_allowed() {
+    let client = client(&["admins@example.com", "team-*@example.com"], &[]);
+
+    assert!(client.allows_groups(&emails(&["admins@example.com"])));
+    assert!(client.allows_groups(&emails(&["users@example.com", "team-ops@example.com"])));
+    assert!(!client.allows_groups(&emails(&["users@example.com"])));
+    assert!(!client.allows_groups(&[]));
+  }
+
+  #[test]
+  fn allows_groups_denied() {
+    let client = client(&[], &["contractors@example.com"]);
+
+    assert!(client.allows_groups(&emails(&["users@example.com"])));
+    assert!(client.allows_groups(&[]));
+    assert!(!client.allows_groups(&emails(&["users@example.com", "contractors@example.com"])));
+  }
+
+  #[test]
+  fn allows_groups_deny_takes_precedence() {
+    let client = client(&["*@example.com"], &["contractors@example.com"]);
+
+    assert!(client.allows_groups(&emails(&["users@example.com"])));
+    assert!(!client.allows_groups(&emails(&["contractors@example.com"])));
+    assert!(!client.allows_groups(&emails(&["users@example.com", "contractors@example.com"])));
+  }
+}
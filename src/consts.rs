@@ -1,10 +1,10 @@
-use std::cell::OnceCell;
+use std::{cell::OnceCell, str::FromStr};
 
 use axum::http::{header, HeaderName};
-use serde::Deserialize;
-use url::Url;
 use worker::{send::SendWrapper, Env};
 
+use crate::handler_error::HandlerError;
+
 // ---------- SECRETS ----------
 
 struct CachedSecret {
@@ -45,11 +45,10 @@ macro_rules! secrets {
 // Implicitly creates the public `Secret` enum and private
 // `SECRETS_ARRAY`.
 secrets!(
-  9,
+  8,
   CLIENT_SECRETS,
+  COOKIE_SECRET,
   WORKER_DOMAIN,
-  JWK_PRIVATE,
-  JWK_PUBLIC,
   GOOGLE_ADMIN_EMAIL,
   GOOGLE_CLIENT_ID,
   GOOGLE_CLIENT_SECRET,
@@ -87,6 +86,33 @@ pub fn get_secret(env: &Env, id: Secret) -> &str {
   get_or_init(env, &SECRETS_ARRAY[id.as_index()])
 }
 
+/// Domains of the comma separated `GOOGLE_WORKSPACE_DOMAIN`, which
+/// can list a workspace's secondary and alias domains, or several
+/// workspaces
+pub fn workspace_domains(env: &Env) -> impl Iterator<Item = &str> {
+  get_secret(env, Secret::GOOGLE_WORKSPACE_DOMAIN)
+    .split(',')
+    .map(str::trim)
+    .filter(|domain| !domain.is_empty())
+}
+
+/// Whether `domain` is one of the `GOOGLE_WORKSPACE_DOMAIN`s
+pub fn is_workspace_domain(env: &Env, domain: &str) -> bool {
+  // domains are case insensitive
+  workspace_domains(env).any(|workspace| workspace.eq_ignore_ascii_case(domain))
+}
+
+/// Get a secret only some features need, so deployments without the
+/// feature don't have to set it. Errors if the secret is missing.
+pub fn get_optional_secret(
+  env: &Env,
+  name: &'static str
+) -> Result<String, HandlerError> {
+  env.secret(name)
+    .map(|secret| secret.to_string())
+    .map_err(|_| HandlerError::MissingSecret(name))
+}
+
 fn get_or_init(
   env: &Env,
   secret: &'static SendWrapper<CachedSecret>
@@ -102,9 +128,22 @@ fn get_or_init(
   })
 }
 
-#[derive(Deserialize)]
-pub struct ClientSecret {
-  pub redirect_uris: Vec<Url>,
+// ---------- VARS ----------
+
+/// Parse an optional `[vars]` value from wrangler.toml, or return
+/// `default` if it isn't set.
+pub fn get_var<T: FromStr>(
+  env: &Env,
+  name: &'static str,
+  default: T
+) -> Result<T, HandlerError> {
+  let Ok(var) = env.var(name) else {
+    return Ok(default)
+  };
+
+  var.to_string()
+    .parse()
+    .map_err(|_| HandlerError::InvalidVar(name))
 }
 
 // ---------- CONSTANTS ----------
@@ -116,6 +155,29 @@ macro_rules! constant {
   }
 }
 
+// Optional secrets
+
+constant!(KEY_ENCRYPTION_KEY);
+constant!(JWK_PRIVATE);
+constant!(JWK_PUBLIC);
+constant!(PAIRWISE_SUBJECT_SALT);
+constant!(ADMIN_API_TOKEN);
+
+// Vars
+
+constant!(ID_TOKEN_LIFETIME);
+constant!(ACCESS_TOKEN_LIFETIME);
+constant!(SIGNING_ALGORITHM);
+constant!(SIGNING_KEY_ROTATION_DAYS);
+constant!(CUSTOM_SCOPES);
+constant!(NESTED_GROUPS);
+constant!(MAX_GROUPS);
+constant!(GROUP_MAPPING);
+constant!(ALLOW_EXTERNAL_ACCOUNTS);
+constant!(ROLE_MAPPING);
+constant!(GROUPS_CACHE_TTL);
+constant!(GROUPS_CACHE_MAX_STALE);
+
 // KV
 
 constant!(KV_AUTHORIZE_STATE);
@@ -126,6 +188,14 @@ constant!(KV_REFRESH_TOKEN_STATE);
 constant!(KV_CACHE);
 constant!(KEY_PROVIDER_METADATA);
 constant!(KEY_SERVICEACCOUNT_OAUTH_TOKEN);
+constant!(KEY_SIGNING_KEYS);
+// prefix of the claims stored per access token
+constant!(KEY_USERINFO);
+// prefix of each user's cached groups, renamed from `KEY_USER_GROUPS`
+// when group IDs were added
+constant!(KEY_CACHED_GROUPS);
+// prefix of the redirect_uris listed at each sector_identifier_uri
+constant!(KEY_SECTOR_IDENTIFIER);
 
 // ---------- TOKEN HEADER ----------
 
@@ -0,0 +1,86 @@
+use axum::{extract::{Path, State}, http::{header, HeaderMap, StatusCode}, response::{IntoResponse, Response}};
+use sha2::{Digest, Sha256};
+use worker::{console_error, Env};
+
+use crate::{consts::{get_optional_secret, ADMIN_API_TOKEN}, groups::invalidate_cached_groups, signing_keys::rotate_signing_keys};
+
+/// Drop a user's cached groups after their memberships changed.
+/// Authorized with the `ADMIN_API_TOKEN` secret as a Bearer token.
+/// Without the secret, admin endpoints are disabled.
+#[worker::send]
+pub async fn invalidate_groups(
+  State(env): State<Env>,
+  headers: HeaderMap,
+  Path(user_email): Path<String>
+) -> Response {
+  if let Some(response) = admin_denial(&env, &headers) {
+    return response
+  }
+
+  match invalidate_cached_groups(&env, &user_email).await {
+    Ok(()) => StatusCode::NO_CONTENT.into_response(),
+    Err(e) => {
+      // TODO: telemetry?
+      console_error!("{e}");
+
+      StatusCode::INTERNAL_SERVER_ERROR.into_response()
+    }
+  }
+}
+
+/// Promote the `next` signing keys now instead of at the end of the
+/// rotation period, e.g. after an active key leaked
+#[worker::send]
+pub async fn rotate_keys(
+  State(env): State<Env>,
+  headers: HeaderMap
+) -> Response {
+  if let Some(response) = admin_denial(&env, &headers) {
+    return response
+  }
+
+  match rotate_signing_keys(&env, true).await {
+    Ok(_) => StatusCode::NO_CONTENT.into_response(),
+    Err(e) => {
+      // TODO: telemetry?
+      console_error!("{e}");
+
+      StatusCode::INTERNAL_SERVER_ERROR.into_response()
+    }
+  }
+}
+
+/// The response to return unless the request is from an admin
+fn admin_denial(env: &Env, headers: &HeaderMap) -> Option<Response> {
+  let admin_token = match get_optional_secret(env, ADMIN_API_TOKEN) {
+    Ok(ok) => ok,
+    Err(e) => {
+      // TODO: telemetry?
+      console_error!("{e}");
+
+      return Some(StatusCode::NOT_FOUND.into_response())
+    }
+  };
+
+  if !is_admin(&admin_token, headers) {
+    // Source: https://www.rfc-editor.org/rfc/rfc6750#section-3.1
+    return Some((
+      StatusCode::UNAUTHORIZED,
+      [(header::WWW_AUTHENTICATE, r#"Bearer error="invalid_token""#)]
+    ).into_response())
+  }
+
+  None
+}
+
+fn is_admin(admin_token: &str, headers: &HeaderMap) -> bool {
+  let Some(token) = headers.get(header::AUTHORIZATION)
+    .and_then(|value| value.to_str().ok())
+    .and_then(|value| value.strip_prefix("Bearer "))
+    else {
+      return false
+    };
+
+  // Comparing hashes doesn't leak how much of the token matched
+  Sha256::digest(token) == Sha256::digest(admin_token)
+}
@@ -1,13 +1,11 @@
-use std::collections::BTreeMap;
-
-use axum::{extract::State, response::{IntoResponse, Redirect, Response}, Form};
+use axum::{extract::{RawForm, State}, http::{header, StatusCode}, response::{IntoResponse, Redirect, Response}};
 use chrono::Duration;
 use openidconnect::{core::CoreAuthErrorResponseType, Scope};
 use serde::Deserialize;
 use url::Url;
 use worker::{console_error, kv::KvStore, Env};
 
-use crate::{consts::{get_secret, ClientSecret, Secret, KV_AUTHORIZE_STATE}, endpoints::authorize::authorize_error::{error, error_response, ErrorParams, ErrorResponse}, handler_error::HandlerError, google::{get_google_auth_url, GoogleAuthorize}, scope::parse_scopes, state::{kv_put, AuthorizeStateRef}};
+use crate::{browser_binding::{new_browser_binding, BrowserBinding}, claims::{google_scopes, released_claims, ClaimsRequest}, clients::get_client, consts::{get_secret, Secret, KV_AUTHORIZE_STATE}, endpoints::authorize::authorize_error::{error, error_response, ErrorParams, ErrorResponse}, handler_error::HandlerError, google::{get_google_auth_url, GoogleAuthorize}, resource::{authorize_resources, parse_form}, scope::{parse_scopes, scope_registry}, state::{kv_put, AuthorizeStateRef}};
 
 /// legal values of the response_type field
 #[derive(Deserialize)]
@@ -27,29 +25,24 @@ pub struct AuthorizeParams {
   redirect_uri: Url,
   state: String,
   nonce: String,
-  scope: String
+  scope: String,
+  /// JSON encoded `ClaimsRequest`
+  claims: Option<String>,
+  /// space delimited, in order of preference
+  acr_values: Option<String>
 }
 
 #[worker::send]
 pub async fn authorize(
   State(env): State<Env>,
-  Form(AuthorizeParams {
-    response_type: _,
-    client_id,
-    redirect_uri,
-    state,
-    nonce,
-    scope
-  }): Form<AuthorizeParams>
+  RawForm(form): RawForm
 ) -> Response {
-  match authorize_result(
-    env,
-    client_id,
-    scope,
-    &redirect_uri,
-    nonce,
-    &state
-  ).await {
+  let Ok((params, resources)) = parse_form::<AuthorizeParams>(&form) else {
+    // Without a valid redirect_uri there's nowhere to send an error
+    return StatusCode::BAD_REQUEST.into_response()
+  };
+
+  match authorize_result(&env, &params, &resources).await {
     Ok(ok) => ok,
     Err(e) => {
       // TODO: telemetry?
@@ -65,60 +58,70 @@ pub async fn authorize(
       };
 
       error_response(
-        redirect_uri,
+        params.redirect_uri,
         ErrorResponse {
           params: response_params,
-          state: &state
+          state: &params.state,
+          iss: get_secret(&env, Secret::WORKER_DOMAIN)
         }
       )
     },
   }
 }
 
-async fn authorize_result<'a>(
-  env: Env,
-  client_id: String,
-  scope: String,
-  client_redirect: &Url,
-  client_nonce: String,
-  client_state: &str,
+async fn authorize_result(
+  env: &Env,
+  AuthorizeParams {
+    response_type: _,
+    client_id,
+    redirect_uri: client_redirect,
+    state: client_state,
+    nonce: client_nonce,
+    scope,
+    claims,
+    acr_values
+  }: &AuthorizeParams,
+  resources: &[String]
 ) -> Result<Response, HandlerError> {
-  let groups_scope = &mut false;
-  let openid_scope = &mut false;
-
-  let scopes = parse_scopes(&scope)?.filter_map(
-    |s| match s {
-      "groups" => {
-        // we accept "groups", but Google doesn't
-        *groups_scope = true;
-        None
-      },
-      "openid" => {
-        *openid_scope = true;
-        None
-      },
-      _ => Some(Scope::new(s.into()))
+  let registry = scope_registry(env)?;
+
+  let mut openid_scope = false;
+  let mut granted_scopes = Vec::new();
+  let mut forwarded_scopes = Vec::new();
+
+  // Scopes we grant ourselves aren't forwarded to Google, unknown
+  // ones are.
+  for s in parse_scopes(scope)? {
+    match s {
+      "openid" => openid_scope = true,
+      s if registry.contains_key(s) => granted_scopes.push(s),
+      _ => forwarded_scopes.push(s)
     }
-  ).collect::<Vec<_>>();
+  }
 
   // Source: https://openid.net/specs/openid-connect-core-1_0.html#AuthRequestValidation
-  if !*openid_scope {
+  if !openid_scope {
     return error(
       CoreAuthErrorResponseType::InvalidScope,
       r#"scope field must contain "openid""#.into()
     )
   }
   
+  // Source: https://openid.net/specs/openid-connect-core-1_0.html#ClaimsParameter
+  let claims = match claims.as_deref().map(serde_json::from_str::<ClaimsRequest>) {
+    None => None,
+    Some(Ok(claims)) => Some(claims),
+    Some(Err(_)) => return error(
+      CoreAuthErrorResponseType::InvalidRequest,
+      "Invalid claims parameter".into()
+    )
+  };
+
   // TODO: implement the "token" and "id_token" flows
   //  https://www.rfc-editor.org/rfc/rfc6749.html#section-4.2.1
   //  https://openid.net/specs/openid-connect-core-1_0.html#Authentication
 
-  // TODO: support loading client ids and redirects in a more generic way
-  let client_secret = get_secret(&env, Secret::CLIENT_SECRETS);
-  let client_map = serde_json::from_str::<
-    BTreeMap<&str, ClientSecret>
-  >(client_secret)?;
-  let Some(client_secret) = client_map.get(client_id.as_str()) else {
+  let Some(client_secret) = get_client(env, client_id)? else {
     return error(
       CoreAuthErrorResponseType::AccessDenied,
       "Unregistered client_id".into()
@@ -136,29 +139,59 @@ async fn authorize_result<'a>(
     )
   }
 
+  let resources = authorize_resources(&client_secret, resources)?;
+
+  // Ask Google for whatever the released claims are taken from
+  let released = released_claims(
+    &registry,
+    granted_scopes.iter().copied(),
+    claims.as_ref(),
+    None
+  );
+  let google_scopes = google_scopes(&released)
+    .into_iter()
+    .chain(forwarded_scopes)
+    .map(|s| Scope::new(s.into()));
+
   // Add the scopes from the client request
   // to the Google request, but no other params.
   let GoogleAuthorize {
     redirect: google_redirect,
     csrf: google_csrf,
     nonce: google_nonce
-  } = get_google_auth_url(&env, scopes)
+  } = get_google_auth_url(env, google_scopes)
     .await?;
 
+  // Bind the flow to this browser so `/callback` can't be
+  // completed by anyone else holding Google's `state`.
+  let BrowserBinding {
+    binding,
+    set_cookie
+  } = new_browser_binding(env);
+
   // Key by csrf and serialize authorize_state
   kv_put(
-    &KvStore::from_this(&env, KV_AUTHORIZE_STATE)?,
+    &KvStore::from_this(env, KV_AUTHORIZE_STATE)?,
     google_csrf.secret(),
     &AuthorizeStateRef {
-      client_id: &client_id,
+      client_id,
       client_redirect,
       client_state,
-      client_nonce: &client_nonce,
+      client_nonce,
       google_nonce: &google_nonce,
-      groups_scope: *groups_scope
+      scopes: granted_scopes,
+      claims: claims.as_ref(),
+      acr_values: acr_values.as_deref()
+        .map(|values| values.split(' ').filter(|v| !v.is_empty()).collect())
+        .unwrap_or_default(),
+      resources: resources.iter().map(String::as_str).collect(),
+      browser_binding: &binding
     },
     Duration::minutes(10)
   ).await?;
 
-  Ok(Redirect::to(google_redirect.as_str()).into_response())
+  Ok((
+    [(header::SET_COOKIE, set_cookie)],
+    Redirect::to(google_redirect.as_str())
+  ).into_response())
 }
@@ -11,11 +11,13 @@ use crate::handler_error::HandlerError;
 /// Sources:
 ///   https://www.rfc-editor.org/rfc/rfc6749.html#section-4.1.2.1
 ///   https://openid.net/specs/openid-connect-core-1_0.html#AuthError
+///   https://www.rfc-editor.org/rfc/rfc9207.html#section-2
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ErrorResponse<'a> {
   #[serde(flatten)]
   pub params: ErrorParams,
   pub state: &'a str,
+  pub iss: &'a str
 }
 #[derive(Serialize, Deserialize, thiserror::Error, Debug)]
 pub struct ErrorParams {
@@ -1,11 +1,10 @@
-use axum::{extract::{Query, State}, http::{header, StatusCode}, response::{IntoResponse, Response}};
+use axum::{extract::{Query, State}, http::{header, HeaderMap, StatusCode}, response::{IntoResponse, Response}};
 use chrono::Duration;
-use openidconnect::{core::CoreAuthErrorResponseType, AuthorizationCode, Nonce};
+use openidconnect::{core::CoreAuthErrorResponseType, AuthorizationCode};
 use serde::Deserialize;
-use url::Url;
 use worker::{console_error, kv::KvStore, Env};
 
-use crate::{consts::{KV_ACCESS_TOKEN_STATE, KV_AUTHORIZE_STATE}, endpoints::authorize_error::{error_response, ErrorResponse}, handler_error::HandlerError, oidc_token::new_token, state::{kv_get, kv_put, AccessTokenStateRef, AuthorizeState, CommonTokenStateRef}};
+use crate::{authentication::{authenticate, authentication_denial, Authentication}, browser_binding::{clear_browser_binding, verify_browser_binding}, clients::get_client, consts::{get_secret, Secret, KV_ACCESS_TOKEN_STATE, KV_AUTHORIZE_STATE}, endpoints::authorize_error::{error, error_response, ErrorResponse}, google::fetch_google_access_token, groups::get_user_groups, handler_error::HandlerError, oidc_token::new_token, state::{kv_get, kv_put, AccessTokenStateRef, AuthorizeState, CommonTokenStateRef}};
 
 use super::authorize_error::ErrorParams;
 
@@ -28,23 +27,30 @@ pub struct CallbackParams {
 #[worker::send]
 pub async fn callback(
   State(env): State<Env>,
-  Query(CallbackParams {
+  headers: HeaderMap,
+  Query(params): Query<CallbackParams>
+) -> Response {
+  // The binding cookie is single use regardless of the outcome
+  (
+    [(header::SET_COOKIE, clear_browser_binding())],
+    callback_response(&env, &headers, params).await
+  ).into_response()
+}
+
+async fn callback_response(
+  env: &Env,
+  headers: &HeaderMap,
+  CallbackParams {
     params,
     // We send `state` to Google in the /authorize request. It expires
     // after 10 minutes, and Google must redirect back to this endpoint
     // with `state` untouched, so it's effectively a one-time password.
     state: google_state
-  }): Query<CallbackParams>
+  }: CallbackParams
 ) -> Response {
-  let AuthorizeState {
-    client_id,
-    client_redirect,
-    client_state,
-    client_nonce,
-    google_nonce,
-    groups_scope
-  } = match fetch_authorize_state(
-      &env,
+  let authorize_state = match fetch_authorize_state(
+      env,
+      headers,
       &google_state
     ).await {
       Ok(ok) => ok,
@@ -56,6 +62,9 @@ pub async fn callback(
         return StatusCode::UNAUTHORIZED.into_response()
       }
     };
+
+  // Source: https://www.rfc-editor.org/rfc/rfc9207.html#section-2
+  let iss = get_secret(env, Secret::WORKER_DOMAIN);
   
   let google_code = match params {
     CallbackEnum::Callback { code } => code,
@@ -69,39 +78,42 @@ pub async fn callback(
       );
 
       return error_response(
-        client_redirect,
+        authorize_state.client_redirect,
         ErrorResponse {
           params,
-          state: &client_state
+          state: &authorize_state.client_state,
+          iss
         }
       )
     },
   };
 
   match callback_result(
-    &env,
-    &google_code,
-    &client_id,
-    client_redirect.clone(),
-    &client_state,
-    &client_nonce,
-    &google_nonce,
-    groups_scope
+    env,
+    google_code,
+    &authorize_state,
+    iss
   ).await {
     Ok(ok) => ok,
     Err(e) => {
       // TODO: telemetry?
       console_error!("{e}");
 
+      let response_params = match e {
+        HandlerError::Authorize(params) => params,
+        _ => ErrorParams {
+          error: CoreAuthErrorResponseType::ServerError,
+          error_description: None,
+          error_uri: None
+        }
+      };
+
       error_response(
-        client_redirect,
+        authorize_state.client_redirect,
         ErrorResponse {
-          params: ErrorParams {
-            error: CoreAuthErrorResponseType::ServerError,
-            error_description: None,
-            error_uri: None
-          },
-          state: &client_state
+          params: response_params,
+          state: &authorize_state.client_state,
+          iss
         }
       )
     },
@@ -110,6 +122,7 @@ pub async fn callback(
 
 async fn fetch_authorize_state(
   env: &Env,
+  headers: &HeaderMap,
   google_state: &str
 ) -> Result<AuthorizeState, HandlerError> {
   let kv_name = KV_AUTHORIZE_STATE;
@@ -117,7 +130,7 @@ async fn fetch_authorize_state(
   let kv = KvStore::from_this(env, kv_name)?;
 
   // use google_state as the key for the authorization state
-  let Some(authorize_state) = kv_get(&kv, google_state)
+  let Some(authorize_state) = kv_get::<AuthorizeState>(&kv, google_state)
     .await?
     else {
       return Err(HandlerError::KvMissing {
@@ -126,23 +139,90 @@ async fn fetch_authorize_state(
       })
     };
 
+  // The state is single use, so delete it before anything else
+  // can fail.
+  kv.delete(google_state).await?;
+
+  // Ensure this is the same browser that started the flow
+  verify_browser_binding(
+    env,
+    headers,
+    &authorize_state.browser_binding
+  )?;
+
   Ok(authorize_state)
 }
 
 async fn callback_result(
   env: &Env,
-  google_code: &AuthorizationCode,
-  client_id: &str,
-  mut client_redirect: Url,
-  client_state: &str,
-  client_nonce: &str,
-  google_nonce: &Nonce,
-  groups_scope: bool
+  google_code: AuthorizationCode,
+  AuthorizeState {
+    client_id,
+    client_redirect,
+    client_state,
+    client_nonce,
+    google_nonce,
+    scopes,
+    claims,
+    acr_values,
+    resources,
+    browser_binding: _
+  }: &AuthorizeState,
+  iss: &str
 ) -> Result<Response, HandlerError> {
+  let Some(client) = get_client(env, client_id)? else {
+    // removed from `CLIENT_SECRETS` during the login
+    return error(
+      CoreAuthErrorResponseType::AccessDenied,
+      "Unregistered client_id".into()
+    )
+  };
+
+  // Exchange Google's code here rather than at `/token`, so a login
+  // that fails a policy can be redirected back with an error.
+  let google_token = fetch_google_access_token(
+    env,
+    google_code,
+    google_nonce
+  ).await?;
+
+  let authentication = authenticate(
+    env,
+    &client,
+    acr_values,
+    claims.as_ref(),
+    &google_token
+  ).await?;
+
+  if let Some(reason) = authentication_denial(
+    env,
+    &client,
+    acr_values,
+    claims.as_ref(),
+    &google_token,
+    &authentication
+  )? {
+    return error(CoreAuthErrorResponseType::AccessDenied, reason.into())
+  }
+
+  let Authentication { amr, acr } = authentication;
+
+  // Deny users outside the client's allowed groups here, so the
+  // client gets an error instead of a code `/token` won't redeem.
+  // `/token` checks again, so refreshes stop once a user leaves.
+  if client.has_group_policy()
+    && !get_user_groups(env, &google_token.user_email).await?.allowed_by(&client)
+  {
+    return error(
+      CoreAuthErrorResponseType::AccessDenied,
+      "User isn't in a group allowed to use this client".into()
+    )
+  }
+
   // Generate our own code
   let client_code = new_token::<16>();
 
-  // Store the client id, redirect, csrf, google_code,
+  // Store the client id, redirect, csrf, Google's tokens,
   // and google_nonce using client_code as the key.
   kv_put(
     &KvStore::from_this(env, KV_ACCESS_TOKEN_STATE)?,
@@ -152,10 +232,18 @@ async fn callback_result(
         client_id,
         client_nonce,
         google_nonce,
-        groups_scope
+        scopes: scopes.iter().map(String::as_str).collect(),
+        groups_scope: false,
+        claims: claims.as_ref(),
+        // Kept for refreshes, which don't re-authenticate the user
+        auth_time: google_token.auth_time,
+        amr: amr.iter().map(String::as_str).collect(),
+        acr: acr.as_deref(),
+        acr_values: acr_values.iter().map(String::as_str).collect(),
+        resources: resources.iter().map(String::as_str).collect()
       },
-      client_redirect: &client_redirect,
-      google_code
+      client_redirect,
+      google_token: &google_token
     },
     Duration::minutes(10)
   ).await?;
@@ -164,13 +252,16 @@ async fn callback_result(
   // 
   // Google redirected the browser to this endpoint `/callback`.
   // Redirect the browser again back to the client's redirect_uri.
+  let mut client_redirect = client_redirect.clone();
   let mut query = client_redirect.query_pairs_mut();
   query.append_pair("code", &client_code);
   query.append_pair("state", client_state);
+  // Source: https://www.rfc-editor.org/rfc/rfc9207.html#section-2
+  query.append_pair("iss", iss);
   drop(query);
 
   Ok((
     StatusCode::FOUND,
     [(header::LOCATION, client_redirect.as_str())]
   ).into_response())
-}
\ No newline at end of file
+}
@@ -1,19 +1,26 @@
-use axum::{extract::State, Json};
+use axum::{extract::State, http::StatusCode, response::{IntoResponse, Response}, Json};
 use serde_json::json;
-use worker::Env;
+use worker::{console_error, Env};
 
-use crate::consts::{get_secret, Secret};
+use crate::signing_keys::{get_signing_keys, published_keys};
 
-// TODO: this doesn't need to be async
 #[worker::send]
-pub async fn jwks(State(env): State<Env>) -> Json<serde_json::Value> {
-  let jwk_public: serde_json::Value = serde_json::from_str(
-    get_secret(&env, Secret::JWK_PUBLIC)
-  ).unwrap();
+pub async fn jwks(State(env): State<Env>) -> Response {
+  let signing_keys = match get_signing_keys(&env).await {
+    Ok(ok) => ok,
+    Err(e) => {
+      // TODO: telemetry?
+      console_error!("{e}");
 
+      return StatusCode::INTERNAL_SERVER_ERROR.into_response()
+    }
+  };
+
+  // Publish every unexpired key so tokens signed before a rotation
+  // still verify, and clients can cache upcoming keys.
   Json(
     json!({
-      "keys": [jwk_public]
+      "keys": published_keys(&signing_keys).collect::<Vec<_>>()
     })
-  )
-}
\ No newline at end of file
+  ).into_response()
+}
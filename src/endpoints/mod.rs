@@ -1,7 +1,9 @@
+mod admin;
 mod authorize;
 mod callback;
 mod token;
 mod jwks;
+mod userinfo;
 pub mod well_known;
 
 pub use authorize::authorize_error;
@@ -12,4 +14,8 @@ pub use token::token;
 
 pub use callback::callback;
 
-pub use jwks::jwks;
\ No newline at end of file
+pub use admin::{invalidate_groups, rotate_keys};
+
+pub use jwks::jwks;
+
+pub use userinfo::{store_userinfo, userinfo};
\ No newline at end of file
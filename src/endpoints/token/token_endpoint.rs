@@ -1,10 +1,11 @@
-use axum::{extract::State, http::StatusCode, response::{IntoResponse, Response}, Form, Json};
+use axum::{extract::{RawForm, State}, http::StatusCode, response::{IntoResponse, Response}, Json};
+use chrono::Duration;
 use openidconnect::{core::CoreErrorResponseType, AuthorizationCode};
 use serde::{Deserialize, Serialize};
 use url::Url;
 use worker::{console_error, kv::KvStore, Env};
 
-use crate::{consts::{KV_ACCESS_TOKEN_STATE, KV_REFRESH_TOKEN_STATE, TOKEN_HEADER}, endpoints::token_error::{error, error_response}, google::{fetch_google_access_token, fetch_google_refresh_token, GoogleIdToken}, groups::get_user_groups, handler_error::HandlerError, oidc_token::{create_oidc_token, new_token}, state::{kv_get, store_refresh_token_state, AccessTokenState, CommonTokenState, RefreshTokenState}};
+use crate::{authentication::{authenticate, authentication_denial}, claims::{released_claims, user_claims, ClaimSources, ClaimTarget}, clients::{get_client, ClientSecret}, consts::{KV_ACCESS_TOKEN_STATE, KV_REFRESH_TOKEN_STATE, TOKEN_HEADER}, endpoints::{store_userinfo, token_error::{error, error_response}}, google::{fetch_google_refresh_token, GoogleIdToken}, groups::get_user_groups, handler_error::HandlerError, oidc_token::{create_access_token, create_oidc_token, new_token}, resource::{parse_form, token_resources}, roles::user_roles, scope::scope_registry, state::{kv_get, store_refresh_token_state, AccessTokenState, CommonTokenState, RefreshTokenState}, users::directory_claims};
 
 // Sources:
 //  https://serde.rs/enum-representations.html#internally-tagged
@@ -54,11 +55,17 @@ pub struct TokenResponse {
 #[worker::send]
 pub async fn token(
   State(env): State<Env>,
-  Form(params): Form<Params>
+  RawForm(form): RawForm
 ) -> Response {  
-  let result = match params {
-    Params::Code(c) => access_token(c, env).await,
-    Params::Refresh{ refresh_token: r } => refresh_token(r, env).await
+  let result = match parse_form::<Params>(&form) {
+    Ok((Params::Code(c), resources)) =>
+      access_token(c, &resources, env).await,
+    Ok((Params::Refresh{ refresh_token: r }, resources)) =>
+      refresh_token(r, &resources, env).await,
+    Err(e) => error(
+      CoreErrorResponseType::InvalidRequest,
+      Some(e.to_string())
+    )
   };
 
   match result {
@@ -82,22 +89,18 @@ pub async fn token(
 
 async fn access_token(
   CodeParams { code, redirect_uri, client_id }: CodeParams,
+  resources: &[String],
   env: Env
 ) -> Result<Response, HandlerError> {
+  let kv = KvStore::from_this(&env, KV_ACCESS_TOKEN_STATE)?;
+
   // fetch access token state
   let Some(AccessTokenState {
-    common: CommonTokenState {
-      client_id: stored_client_id,
-      client_nonce,
-      google_nonce,
-      groups_scope
-    },
+    mut common,
     client_redirect,
-    google_code
-  }) = kv_get(
-    &KvStore::from_this(&env, KV_ACCESS_TOKEN_STATE)?,
-    code.secret()
-  ).await?
+    google_token
+  }) = kv_get::<AccessTokenState>(&kv, code.secret())
+    .await?
     else {
       // No stored access token = user isn't authorized
       return error(
@@ -106,8 +109,16 @@ async fn access_token(
       )
     };
 
+  common.upgrade_legacy_scopes();
+
+  // Codes are single use, so delete it before anything else can fail.
+  // Google's code was already exchanged at `/callback`, so nothing
+  // else stops the stored tokens being redeemed again.
+  // Source: https://www.rfc-editor.org/rfc/rfc6749#section-4.1.2
+  kv.delete(code.secret()).await?;
+
   // ensure matching client_id
-  if client_id != stored_client_id {
+  if client_id != common.client_id {
     return error(
       CoreErrorResponseType::InvalidGrant,
       Some("Invalid code".into())
@@ -121,41 +132,21 @@ async fn access_token(
     )
   }
 
-  // get access and refresh tokens
-  let GoogleIdToken {
-    refresh_token: google_refresh,
-    user_email,
-    subject: google_subject,
-    issue_time,
-    expiration
-  } = fetch_google_access_token(
-    &env,
-    google_code,
-    &google_nonce
-  ).await?;
-
-  // query google group endpoint for our user's group membership
-  let groups = if groups_scope {
-    Some(get_user_groups(&env, &user_email).await?)
-  } else {
-    None
-  };
+  let client = registered_client(&env, &client_id)?;
 
   // generate client tokens
-  let access_token = create_oidc_token(
+  let (access_token, id_token) = issue_tokens(
     &env,
-    &client_id,
-    &client_nonce,
-    google_subject,
-    issue_time,
-    expiration,
-    groups
-  )?;
+    &client,
+    &common,
+    &google_token,
+    resources
+  ).await?;
 
   // If google gave us a refresh token (it should),
   // cache our own refresh token along with google's
   // access+refresh token for one year.
-  let refresh_token = match google_refresh {
+  let refresh_token = match google_token.refresh_token {
     None => None,
     Some(google_refresh) => {
       let client_refresh = new_token::<32>();
@@ -164,12 +155,7 @@ async fn access_token(
         &env,
         &client_refresh,
         &RefreshTokenState {
-          common: CommonTokenState {
-            client_id,
-            client_nonce,
-            google_nonce,
-            groups_scope,
-          },
+          common,
           google_refresh
         }
       ).await?;
@@ -178,17 +164,23 @@ async fn access_token(
     }
   };
 
-  Ok(token_response(access_token, refresh_token))
+  Ok(token_response(
+    access_token,
+    id_token,
+    refresh_token,
+    client.access_token_lifetime(&env)?
+  ))
 }
 
 async fn refresh_token(
   refresh_token: String,
+  resources: &[String],
   env: Env
 ) -> Result<Response, HandlerError> {
   let kv = KvStore::from_this(&env, KV_REFRESH_TOKEN_STATE)?;
 
   // fetch refresh token state
-  let Some(mut refresh_token_state) = kv_get(&kv, &refresh_token)
+  let Some(mut refresh_token_state) = kv_get::<RefreshTokenState>(&kv, &refresh_token)
     .await?
     else {
       // No stored refresh token = user isn't authorized
@@ -198,62 +190,52 @@ async fn refresh_token(
       )
     };
 
+  refresh_token_state.common.upgrade_legacy_scopes();
+
   let RefreshTokenState {
-    common: CommonTokenState {
-      client_id,
-      client_nonce,
-      google_nonce,
-      groups_scope
-    },
+    common,
     google_refresh
   } = &refresh_token_state;
 
+  let client = registered_client(&env, &common.client_id)?;
+
   // get access and refresh tokens
-  let GoogleIdToken {
-    refresh_token: new_google_refresh,
-    user_email,
-    subject: google_subject,
-    issue_time,
-    expiration
-  } = match fetch_google_refresh_token(
+  let google_token = match fetch_google_refresh_token(
     &env,
     google_refresh,
-    google_nonce
+    &common.google_nonce
   ).await {
     Ok(ok) => ok,
     Err(e) => {
-      match e {
-        // If the Google refresh token is revoked or expired
-        HandlerError::Token(ref t)
-          if *t.error() == CoreErrorResponseType::InvalidGrant =>
-        {
-          // revoke the client's refresh token too
-          kv.delete(&refresh_token).await?;
-        },
-        _ => ()
+      // If the Google refresh token is revoked or expired
+      if is_invalid_grant(&e) {
+        // revoke the client's refresh token too
+        kv.delete(&refresh_token).await?;
       }
 
       return Err(e)
     }
   };
 
-  let groups = if *groups_scope {
-    Some(get_user_groups(&env, &user_email).await?)
-  } else {
-    None
+  let (access_token, id_token) = match issue_tokens(
+    &env,
+    &client,
+    common,
+    &google_token,
+    resources
+  ).await {
+    Ok(ok) => ok,
+    Err(e) => {
+      // If the user is no longer allowed to use the client
+      if is_invalid_grant(&e) {
+        kv.delete(&refresh_token).await?;
+      }
+
+      return Err(e)
+    }
   };
 
-  let id_token = create_oidc_token(
-    &env,
-    client_id,
-    client_nonce,
-    google_subject,
-    issue_time,
-    expiration,
-    groups
-  )?;
-
-  match new_google_refresh {
+  match google_token.refresh_token {
     // This case probably won't occur
     Some(new_google_refresh)
       if new_google_refresh.secret() != google_refresh.secret() =>
@@ -273,17 +255,163 @@ async fn refresh_token(
     _ => (),
   }
 
-  Ok(token_response(id_token, Some(refresh_token)))
+  Ok(token_response(
+    access_token,
+    id_token,
+    Some(refresh_token),
+    client.access_token_lifetime(&env)?
+  ))
+}
+
+/// The grant can't be used anymore, e.g. it was revoked
+fn is_invalid_grant(e: &HandlerError) -> bool {
+  matches!(
+    e,
+    HandlerError::Token(t) if *t.error() == CoreErrorResponseType::InvalidGrant
+  )
+}
+
+/// Generate an access token for `requested_resources` and an ID token,
+/// and store the claims `/userinfo` returns for the access token.
+async fn issue_tokens(
+  env: &Env,
+  client: &ClientSecret,
+  common: &CommonTokenState,
+  google_token: &GoogleIdToken,
+  requested_resources: &[String]
+) -> Result<(String, String), HandlerError> {
+  // The account could have left the workspace or stopped requiring
+  // 2-Step Verification since logging in
+  let authentication = authenticate(
+    env,
+    client,
+    &common.acr_values,
+    common.claims.as_ref(),
+    google_token
+  ).await?;
+
+  if let Some(reason) = authentication_denial(
+    env,
+    client,
+    &common.acr_values,
+    common.claims.as_ref(),
+    google_token,
+    &authentication
+  )? {
+    return error(CoreErrorResponseType::InvalidGrant, Some(reason.into()))
+  }
+
+  let resources = token_resources(&common.resources, requested_resources)?;
+
+  let registry = scope_registry(env)?;
+
+  let released = released_claims(
+    &registry,
+    common.scopes.iter().map(String::as_str),
+    common.claims.as_ref(),
+    None
+  );
+
+  // query google group endpoint for our user's group membership
+  let groups = if released.contains("groups")
+    || released.contains("roles")
+    || client.has_group_policy()
+  {
+    Some(get_user_groups(env, &google_token.user_email).await?)
+  } else {
+    None
+  };
+
+  // The user could have left an allowed group since logging in
+  if groups.as_ref().is_some_and(|groups| client.has_group_policy() && !groups.allowed_by(client)) {
+    return error(
+      CoreErrorResponseType::InvalidGrant,
+      Some("User isn't in a group allowed to use this client".into())
+    )
+  }
+
+  // roles come from every group, not just the client's
+  let roles = match &groups {
+    Some(groups) if released.contains("roles") => Some(user_roles(env, &groups.groups)?),
+    _ => None
+  };
+
+  let directory = directory_claims(env, client, &google_token.user_email).await;
+
+  // keep only the groups the client asked for to shrink its tokens
+  let groups = match groups.filter(|_| released.contains("groups")) {
+    Some(mut groups) => {
+      groups.groups.retain(|group| client.includes_group(group));
+      groups.truncate(env, &google_token.user_email)?;
+      Some(groups.groups)
+    },
+    None => None
+  };
+
+  let sources = ClaimSources {
+    google_token,
+    groups: groups.as_ref(),
+    roles: roles.as_ref(),
+    directory: &directory
+  };
+
+  let id_token_claims = user_claims(env, client, common, &registry, &sources, ClaimTarget::IdToken).await?;
+
+  let access_token = create_access_token(
+    env,
+    client,
+    common,
+    &id_token_claims,
+    &resources
+  ).await?;
+
+  let id_token = create_oidc_token(
+    env,
+    client,
+    common,
+    &id_token_claims,
+    &access_token
+  ).await?;
+
+  store_userinfo(
+    env,
+    &access_token,
+    &user_claims(env, client, common, &registry, &sources, ClaimTarget::Userinfo).await?,
+    client.access_token_lifetime(env)?
+  ).await?;
+
+  Ok((access_token, id_token))
 }
 
-fn token_response(id_token: String, refresh_token: Option<String>) -> Response {
+/// Look up the client a code or refresh token was issued to
+fn registered_client(
+  env: &Env,
+  client_id: &str
+) -> Result<ClientSecret, HandlerError> {
+  match get_client(env, client_id)? {
+    Some(client) => Ok(client),
+    // removed from `CLIENT_SECRETS` since the grant was issued
+    None => error(
+      CoreErrorResponseType::InvalidClient,
+      Some("Unregistered client_id".into())
+    )
+  }
+}
+
+fn token_response(
+  access_token: String,
+  id_token: String,
+  refresh_token: Option<String>,
+  access_token_lifetime: Duration
+) -> Response {
   (
     TOKEN_HEADER,
     // Source: https://openid.net/specs/openid-connect-core-1_0.html#TokenResponse
     Json(TokenResponse {
-      access_token: new_token::<16>(),
+      access_token,
       token_type: "Bearer",
-      expires_in: 3600,
+      // panic if negative
+      expires_in: access_token_lifetime.num_seconds().try_into().unwrap(),
       refresh_token,
       id_token
     })
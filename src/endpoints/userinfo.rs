@@ -0,0 +1,76 @@
+use axum::{extract::State, http::{header, HeaderMap, StatusCode}, response::{IntoResponse, Response}, Json};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::Duration;
+use sha2::{Digest, Sha256};
+use worker::{console_error, kv::KvStore, Env};
+
+use crate::{claims::UserClaims, consts::{KEY_USERINFO, KV_CACHE, TOKEN_HEADER}, handler_error::HandlerError, state::{kv_get, kv_put}};
+
+/// Store the claims `/userinfo` returns for `access_token` until the
+/// access token expires.
+pub async fn store_userinfo(
+  env: &Env,
+  access_token: &str,
+  claims: &UserClaims,
+  access_token_lifetime: Duration
+) -> Result<(), HandlerError> {
+  kv_put(
+    &KvStore::from_this(env, KV_CACHE)?,
+    &userinfo_key(access_token),
+    claims,
+    // the shortest TTL KV accepts
+    access_token_lifetime.max(Duration::seconds(60))
+  ).await
+}
+
+/// Source: https://openid.net/specs/openid-connect-core-1_0.html#UserInfo
+#[worker::send]
+pub async fn userinfo(
+  State(env): State<Env>,
+  headers: HeaderMap
+) -> Response {
+  // Source: https://www.rfc-editor.org/rfc/rfc6750#section-2.1
+  let Some(access_token) = headers.get(header::AUTHORIZATION)
+    .and_then(|value| value.to_str().ok())
+    .and_then(|value| value.strip_prefix("Bearer "))
+    else {
+      // Source: https://www.rfc-editor.org/rfc/rfc6750#section-3.1
+      return (
+        StatusCode::UNAUTHORIZED,
+        [(header::WWW_AUTHENTICATE, "Bearer")]
+      ).into_response()
+    };
+
+  match fetch_userinfo(&env, access_token).await {
+    Ok(Some(claims)) => (TOKEN_HEADER, Json(claims)).into_response(),
+    // unknown or expired access token
+    Ok(None) => (
+      StatusCode::UNAUTHORIZED,
+      [(header::WWW_AUTHENTICATE, r#"Bearer error="invalid_token""#)]
+    ).into_response(),
+    Err(e) => {
+      // TODO: telemetry?
+      console_error!("{e}");
+
+      StatusCode::INTERNAL_SERVER_ERROR.into_response()
+    }
+  }
+}
+
+async fn fetch_userinfo(
+  env: &Env,
+  access_token: &str
+) -> Result<Option<UserClaims>, HandlerError> {
+  kv_get(
+    &KvStore::from_this(env, KV_CACHE)?,
+    &userinfo_key(access_token)
+  ).await
+}
+
+/// Keyed by a hash so the stored keys can't be used as access tokens
+fn userinfo_key(access_token: &str) -> String {
+  format!(
+    "{KEY_USERINFO}/{hash}",
+    hash = URL_SAFE_NO_PAD.encode(Sha256::digest(access_token))
+  )
+}
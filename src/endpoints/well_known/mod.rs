@@ -1,3 +1,3 @@
-mod openid_configuration;
+mod provider_metadata;
 
-pub use openid_configuration::openid_configuration;
\ No newline at end of file
+pub use provider_metadata::{oauth_authorization_server, openid_configuration};
@@ -0,0 +1,137 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use axum::{extract::State, http::{header, HeaderName, StatusCode}, response::{IntoResponse, Response}, Json};
+use serde::Serialize;
+use worker::{console_error, Env};
+
+use crate::{authentication::ACR_MULTI_FACTOR, claims::claim_names, clients::{get_clients, ClientSecret}, consts::{get_secret, Secret}, handler_error::HandlerError, jwe::{ContentEncryption, KeyManagementAlgorithm}, scope::scope_registry, signing_keys::signing_algorithms, subject::SubjectType};
+
+/// Clients and proxies may cache the metadata. It only changes when
+/// the worker or `CLIENT_SECRETS` does.
+const METADATA_HEADER: [(HeaderName, &str); 1] = [
+  (header::CACHE_CONTROL, "public, max-age=3600")
+];
+
+/// Only advertises what the worker actually supports.
+///
+/// Sources:
+///   https://openid.net/specs/openid-connect-discovery-1_0.html#ProviderMetadata
+///   https://www.rfc-editor.org/rfc/rfc8414.html#section-2
+///   https://www.rfc-editor.org/rfc/rfc9207.html#section-3
+#[derive(Serialize)]
+struct ProviderMetadata {
+  issuer: String,
+  authorization_endpoint: String,
+  token_endpoint: String,
+  userinfo_endpoint: String,
+  jwks_uri: String,
+  // TODO: implement the "token" and "id_token" flows
+  response_types_supported: Vec<&'static str>,
+  response_modes_supported: Vec<&'static str>,
+  grant_types_supported: Vec<&'static str>,
+  subject_types_supported: Vec<&'static str>,
+  acr_values_supported: Vec<&'static str>,
+  id_token_signing_alg_values_supported: Vec<&'static str>,
+  id_token_encryption_alg_values_supported: Vec<&'static str>,
+  id_token_encryption_enc_values_supported: Vec<&'static str>,
+  scopes_supported: Vec<String>,
+  claims_supported: Vec<&'static str>,
+  claims_parameter_supported: bool,
+  // Clients are identified by client_id and redirect_uri only
+  token_endpoint_auth_methods_supported: Vec<&'static str>,
+  authorization_response_iss_parameter_supported: bool
+}
+
+fn provider_metadata(env: &Env) -> Result<ProviderMetadata, HandlerError> {
+  let domain = get_secret(env, Secret::WORKER_DOMAIN);
+  let clients = get_clients(env)?;
+
+  Ok(ProviderMetadata {
+    issuer: domain.to_string(),
+    authorization_endpoint: format!("{domain}/authorize"),
+    token_endpoint: format!("{domain}/token"),
+    userinfo_endpoint: format!("{domain}/userinfo"),
+    jwks_uri: format!("{domain}/jwks"),
+    response_types_supported: vec!["code"],
+    response_modes_supported: vec!["query"],
+    grant_types_supported: vec![
+      "authorization_code",
+      "refresh_token"
+    ],
+    subject_types_supported: subject_types(&clients),
+    acr_values_supported: vec![ACR_MULTI_FACTOR],
+    // every algorithm a registered client's ID tokens are signed with
+    id_token_signing_alg_values_supported: signing_algorithms(env)?
+      .into_iter()
+      .map(|alg| alg.as_str())
+      .collect(),
+    id_token_encryption_alg_values_supported: KeyManagementAlgorithm::ALL
+      .iter()
+      .map(|alg| alg.as_str())
+      .collect(),
+    id_token_encryption_enc_values_supported: ContentEncryption::ALL
+      .iter()
+      .map(|enc| enc.as_str())
+      .collect(),
+    scopes_supported: std::iter::once("openid".to_string())
+      .chain(scope_registry(env)?.into_keys())
+      .collect(),
+    claims_supported: [
+      "acr",
+      "amr",
+      "aud",
+      "auth_time",
+      "exp",
+      "iat",
+      "iss",
+      "nonce",
+      "sub"
+    ].into_iter()
+      .chain(claim_names())
+      .collect(),
+    claims_parameter_supported: true,
+    token_endpoint_auth_methods_supported: vec!["none"],
+    authorization_response_iss_parameter_supported: true
+  })
+}
+
+/// Subject types of every registered client
+fn subject_types(clients: &BTreeMap<String, ClientSecret>) -> Vec<&'static str> {
+  let types = clients.values()
+    .map(|client| client.subject_type)
+    .collect::<BTreeSet<_>>();
+
+  if types.is_empty() {
+    return vec![SubjectType::Public.as_str()]
+  }
+
+  types.into_iter()
+    .map(|t| t.as_str())
+    .collect()
+}
+
+fn metadata_response(env: &Env) -> Response {
+  match provider_metadata(env) {
+    Ok(metadata) => (METADATA_HEADER, Json(metadata)).into_response(),
+    Err(e) => {
+      // TODO: telemetry?
+      console_error!("{e}");
+
+      StatusCode::INTERNAL_SERVER_ERROR.into_response()
+    }
+  }
+}
+
+/// Source: https://openid.net/specs/openid-connect-discovery-1_0.html#ProviderConfig
+// TODO: this doesn't need to be async
+#[worker::send]
+pub async fn openid_configuration(State(env): State<Env>) -> Response {
+  metadata_response(&env)
+}
+
+/// Source: https://www.rfc-editor.org/rfc/rfc8414.html#section-3
+// TODO: this doesn't need to be async
+#[worker::send]
+pub async fn oauth_authorization_server(State(env): State<Env>) -> Response {
+  metadata_response(&env)
+}
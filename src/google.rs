@@ -1,9 +1,52 @@
 use chrono::{DateTime, Duration, Utc};
-use openidconnect::{core::{CoreClient, CoreProviderMetadata, CoreRequestTokenError, CoreResponseType, CoreTokenResponse}, reqwest::{async_http_client, AsyncHttpClientError}, AuthenticationFlow, AuthorizationCode, ClientId, ClientSecret, CsrfToken, EndUserEmail, IssuerUrl, Nonce, OAuth2TokenResponse, RedirectUrl, RefreshToken, RequestTokenError::ServerResponse, Scope, SubjectIdentifier};
+use std::ops::Deref;
+
+use openidconnect::{core::{CoreAuthDisplay, CoreAuthPrompt, CoreErrorResponseType, CoreGenderClaim, CoreJsonWebKey, CoreJsonWebKeyType, CoreJsonWebKeyUse, CoreJweContentEncryptionAlgorithm, CoreJwsSigningAlgorithm, CoreProviderMetadata, CoreRequestTokenError, CoreResponseType, CoreRevocableToken, CoreRevocationErrorResponse, CoreTokenIntrospectionResponse, CoreTokenType}, reqwest::{async_http_client, AsyncHttpClientError}, AdditionalClaims, AuthenticationFlow, AuthorizationCode, Client, ClientId, ClientSecret, CsrfToken, EmptyExtraTokenFields, EndUserEmail, IdTokenFields, IssuerUrl, LocalizedClaim, Nonce, OAuth2TokenResponse, RedirectUrl, RefreshToken, RequestTokenError::ServerResponse, Scope, StandardErrorResponse, StandardTokenResponse, SubjectIdentifier};
+use serde::{Deserialize, Serialize};
 use url::Url;
 use worker::{kv::KvStore, Env};
 
-use crate::{consts::{get_secret, Secret, KEY_PROVIDER_METADATA, KV_CACHE}, endpoints::token_error::TokenErrorResponse, handler_error::HandlerError, state::{kv_get, kv_put, StoredProviderMetadata, StoredProviderMetadataRef}};
+use crate::{consts::{get_secret, get_var, workspace_domains, Secret, ALLOW_EXTERNAL_ACCOUNTS, KEY_PROVIDER_METADATA, KV_CACHE}, endpoints::token_error::TokenErrorResponse, handler_error::HandlerError, state::{kv_get, kv_put, StoredProviderMetadata, StoredProviderMetadataRef}};
+
+/// Non-standard claims in Google's ID tokens
+/// Source: https://developers.google.com/identity/openid-connect/openid-connect#an-id-tokens-payload
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct GoogleClaims {
+  /// Workspace domain of the user. Missing for consumer accounts.
+  hd: Option<String>
+}
+impl AdditionalClaims for GoogleClaims {}
+
+type GoogleTokenResponse = StandardTokenResponse<
+  IdTokenFields<
+    GoogleClaims,
+    EmptyExtraTokenFields,
+    CoreGenderClaim,
+    CoreJweContentEncryptionAlgorithm,
+    CoreJwsSigningAlgorithm,
+    CoreJsonWebKeyType
+  >,
+  CoreTokenType
+>;
+
+/// `CoreClient` with `GoogleClaims` instead of no additional claims
+type GoogleClient = Client<
+  GoogleClaims,
+  CoreAuthDisplay,
+  CoreGenderClaim,
+  CoreJweContentEncryptionAlgorithm,
+  CoreJwsSigningAlgorithm,
+  CoreJsonWebKeyType,
+  CoreJsonWebKeyUse,
+  CoreJsonWebKey,
+  CoreAuthPrompt,
+  StandardErrorResponse<CoreErrorResponseType>,
+  GoogleTokenResponse,
+  CoreTokenType,
+  CoreTokenIntrospectionResponse,
+  CoreRevocableToken,
+  CoreRevocationErrorResponse
+>;
 
 /// Retrieve our cached and serialized Google OIDC provider
 /// metadata, or create it if it does not exist.
@@ -47,7 +90,7 @@ async fn get_provider_metadata(env: &Env) -> Result<CoreProviderMetadata, Handle
 }
 
 /// Create a new OpenID Connect client from stored google secrets.
-async fn new_client(env: &Env) -> Result<CoreClient, HandlerError> {
+async fn new_client(env: &Env) -> Result<GoogleClient, HandlerError> {
   // load secrets
 
   let google_client_id = ClientId::new(
@@ -76,7 +119,7 @@ async fn new_client(env: &Env) -> Result<CoreClient, HandlerError> {
 
   // Set up the config for the Google OpenID Connect process.
   Ok(
-    CoreClient::from_provider_metadata(
+    GoogleClient::from_provider_metadata(
       provider_metadata,
       google_client_id,
       Some(google_client_secret),
@@ -97,8 +140,10 @@ pub async fn get_google_auth_url(
   env: &Env,
   scopes: impl IntoIterator<Item = Scope>
 ) -> Result<GoogleAuthorize, HandlerError> {
+  let client = new_client(env).await?;
+
   // Generate the authorization URL to which we'll redirect the user.
-  let (redirect, csrf, nonce) = new_client(env).await?
+  let mut request = client
     .authorize_url(
       AuthenticationFlow::<CoreResponseType>::AuthorizationCode,
       // 23 bytes = 1/10^56 guess chance (UUID is 1/10^38).
@@ -107,18 +152,44 @@ pub async fn get_google_auth_url(
       Nonce::new_random,
     )
     .add_scope(Scope::new("email".to_string()))
-    .add_scopes(scopes)
-    .url();
+    .add_scopes(scopes);
+
+  // Hint Google's account chooser to the workspace's accounts, or to
+  // any workspace's with several domains. Only a hint, `authentication_denial`
+  // checks the `hd` claim.
+  // Source: https://developers.google.com/identity/openid-connect/openid-connect#hd-param
+  if !get_var(env, ALLOW_EXTERNAL_ACCOUNTS, false)? {
+    let hd = match workspace_domains(env).collect::<Vec<_>>()[..] {
+      [domain] => domain,
+      _ => "*"
+    };
+
+    request = request.add_extra_param("hd", hd);
+  }
+
+  let (redirect, csrf, nonce) = request.url();
 
   Ok(GoogleAuthorize { redirect, csrf, nonce })
 }
 
+/// Stored in `AccessTokenState` between `/callback` and `/token`
+#[derive(Serialize, Deserialize)]
 pub struct GoogleIdToken {
   pub refresh_token: Option<RefreshToken>,
   pub user_email: EndUserEmail,
+  pub email_verified: Option<bool>,
   pub subject: SubjectIdentifier,
-  pub issue_time: DateTime<Utc>,
-  pub expiration: DateTime<Utc>
+  pub auth_time: Option<DateTime<Utc>>,
+  /// Google rarely sets these
+  pub amr: Option<Vec<String>>,
+  pub acr: Option<String>,
+  pub hosted_domain: Option<String>,
+  // only present if the "profile" scope was granted
+  pub name: Option<String>,
+  pub given_name: Option<String>,
+  pub family_name: Option<String>,
+  pub picture: Option<String>,
+  pub locale: Option<String>
 }
 /// Exchange the code for a token
 pub async fn fetch_google_access_token(
@@ -153,9 +224,9 @@ pub async fn fetch_google_refresh_token(
 /// Return Google's ServerResponseError (if it did error), otherwise
 /// grab the required values from the token
 fn response_to_token(
-  client: &CoreClient,
+  client: &GoogleClient,
   response: Result<
-    CoreTokenResponse,
+    GoogleTokenResponse,
     CoreRequestTokenError<AsyncHttpClientError>
   >,
   nonce: &Nonce,
@@ -187,9 +258,26 @@ fn response_to_token(
     GoogleIdToken {
       refresh_token: response.refresh_token().cloned(),
       user_email: user_email.clone(),
+      email_verified: claims.email_verified(),
       subject: claims.subject().clone(),
-      issue_time: claims.issue_time(),
-      expiration: claims.expiration(),
+      auth_time: claims.auth_time(),
+      amr: claims.auth_method_refs()
+        .map(|amr| amr.iter().map(|m| m.to_string()).collect()),
+      acr: claims.auth_context_ref().map(|acr| acr.to_string()),
+      hosted_domain: claims.additional_claims().hd.clone(),
+      name: localized(claims.name()),
+      given_name: localized(claims.given_name()),
+      family_name: localized(claims.family_name()),
+      picture: localized(claims.picture()),
+      locale: claims.locale().map(|locale| locale.to_string())
     }
   )
+}
+
+/// Google doesn't localize claims, so take the default value
+fn localized<T: Deref<Target = String>>(
+  claim: Option<&LocalizedClaim<T>>
+) -> Option<String> {
+  claim.and_then(|claim| claim.get(None))
+    .map(|value| value.to_string())
 }
\ No newline at end of file
@@ -0,0 +1,112 @@
+use std::{borrow::Cow, str::FromStr};
+
+use regex::Regex;
+use serde::{de::Error, Deserialize, Deserializer};
+
+/// How groups become `groups` claim values, from the JSON
+/// `GROUP_MAPPING` var, e.g.
+/// `{"rules": [{"regex": "k8s-(\\w+)-(\\w+)", "to": "$1:$2"}], "drop_unmapped": true}`
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GroupMapping {
+  #[serde(default)]
+  format: GroupFormat,
+  /// Tried in order, the first matching rule maps the group
+  #[serde(default)]
+  rules: Vec<GroupRule>,
+  /// Leave out groups no rule matches, instead of including them as is
+  #[serde(default)]
+  drop_unmapped: bool,
+  /// Prefix local parts with their domain, `<domain>/<group_name>`, so
+  /// groups of several workspace domains can't collide
+  #[serde(default)]
+  domain_prefix: bool
+}
+impl FromStr for GroupMapping {
+  type Err = serde_json::Error;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    serde_json::from_str(s)
+  }
+}
+
+/// What identifies a group before any rules apply
+#[derive(Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GroupFormat {
+  /// `<group_name>` of `<group_name>@<domain>`
+  #[default]
+  LocalPart,
+  Email,
+  /// Google's immutable group ID, unchanged by renames
+  Id
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct GroupRule {
+  #[serde(flatten)]
+  pattern: GroupPattern,
+  /// Replacement for the matched group. Regex rules can refer to
+  /// capture groups as `$1` or `${name}`.
+  to: String
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum GroupPattern {
+  Exact(String),
+  /// Replaces the prefix, keeping the rest of the group
+  Prefix(String),
+  /// Has to match the whole group
+  Regex(#[serde(deserialize_with = "deserialize_regex")] Regex)
+}
+
+fn deserialize_regex<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Regex, D::Error> {
+  let pattern = String::deserialize(deserializer)?;
+
+  Regex::new(&format!("^(?:{pattern})$")).map_err(D::Error::custom)
+}
+
+impl GroupMapping {
+  /// The base value of a group in the configured format
+  pub fn base<'a>(
+    &self,
+    local_part: &'a str,
+    domain: &str,
+    email: &'a str,
+    id: &'a str
+  ) -> Cow<'a, str> {
+    match self.format {
+      GroupFormat::LocalPart if self.domain_prefix =>
+        format!("{domain}/{local_part}").into(),
+      GroupFormat::LocalPart => local_part.into(),
+      GroupFormat::Email => email.into(),
+      GroupFormat::Id => id.into()
+    }
+  }
+
+  /// Map a group's base value with the first matching rule, or
+  /// `None` if it should be dropped
+  pub fn map(&self, group: &str) -> Option<String> {
+    for GroupRule { pattern, to } in &self.rules {
+      let mapped = match pattern {
+        GroupPattern::Exact(exact) => (group == exact).then(|| to.clone()),
+        GroupPattern::Prefix(prefix) => group.strip_prefix(prefix.as_str())
+          .map(|rest| format!("{to}{rest}")),
+        GroupPattern::Regex(regex) => regex.captures(group)
+          .map(|captures| {
+            let mut expanded = String::new();
+            captures.expand(to, &mut expanded);
+            expanded
+          })
+      };
+
+      if mapped.is_some() {
+        return mapped
+      }
+    }
+
+    (!self.drop_unmapped).then(|| group.to_string())
+  }
+}
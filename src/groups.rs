@@ -1,11 +1,16 @@
-use chrono::{Duration, Utc};
+use std::collections::BTreeSet;
+
+use chrono::{DateTime, Duration, Utc};
+use itertools::Itertools;
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
 use surrealdb_jsonwebtoken::{encode, Algorithm, EncodingKey, Header};
 use url::Url;
 use worker::{console_error, kv::KvStore, Env};
 
-use crate::{consts::{get_secret, Secret, KEY_SERVICEACCOUNT_OAUTH_TOKEN, KV_CACHE}, handler_error::HandlerError, state::{kv_get, kv_put}};
+use crate::{clients::ClientSecret, consts::{get_secret, get_var, is_workspace_domain, Secret, GROUPS_CACHE_MAX_STALE, GROUPS_CACHE_TTL, GROUP_MAPPING, KEY_CACHED_GROUPS, KEY_SERVICEACCOUNT_OAUTH_TOKEN, KV_CACHE, MAX_GROUPS, NESTED_GROUPS}, group_mapping::GroupMapping, handler_error::HandlerError, state::{kv_get, kv_put}};
+
+const GROUP_SCOPE: &str = "https://www.googleapis.com/auth/admin.directory.group.readonly";
 
 #[derive(Deserialize)]
 struct ServiceAccount {
@@ -31,76 +36,300 @@ struct TokenResponse {
 // Spec: https://developers.google.com/admin-sdk/directory/v1/guides/manage-groups#get_all_member_groups
 #[derive(Deserialize)]
 struct GroupsApiResponse {
-  groups: Vec<GoogleGroup>
+  // missing for members of no groups
+  #[serde(default)]
+  groups: Vec<GoogleGroup>,
+  #[serde(rename = "nextPageToken")]
+  next_page_token: Option<String>
 }
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize)]
 struct GoogleGroup {
+  id: String,
   email: String
 }
 
-/// Returns the names of all groups belonging to `domain` that `user_email` is a member of.
-/// Note: this returns the group email name(s) (<group_name>@<domain>), not the group title.
+/// A user's groups as last fetched from the Admin SDK API
+#[derive(Serialize, Deserialize)]
+struct CachedGroups {
+  groups: Vec<GoogleGroup>,
+  /// The nested group walk stopped before finding every group
+  #[serde(default)]
+  truncated: bool,
+  fetched_at: DateTime<Utc>,
+  /// Older than `GROUPS_CACHE_TTL`, used while the Admin SDK API fails
+  #[serde(skip)]
+  stale: bool
+}
+
+/// A user's groups, mapped by the `GROUP_MAPPING` var
+pub struct UserGroups {
+  pub groups: Vec<String>,
+  /// Emails of every group, before any filtering or mapping
+  pub emails: Vec<String>,
+  /// Some of the user's groups are missing, so decisions based on the
+  /// groups have to fail closed
+  pub truncated: bool,
+  /// The groups could be outdated, same as `truncated`
+  pub stale: bool
+}
+impl UserGroups {
+  /// Whether the user may log in to a client with a group policy. A
+  /// partial or outdated group list could be missing a denied group.
+  pub fn allowed_by(&self, client: &ClientSecret) -> bool {
+    !self.truncated && !self.stale && client.allows_groups(&self.emails)
+  }
+
+  /// Keep at most `MAX_GROUPS` groups. Call after filtering, so the
+  /// cap only drops groups that would have been returned.
+  pub fn truncate(&mut self, env: &Env, user_email: &str) -> Result<(), HandlerError> {
+    let max_groups = get_var(env, MAX_GROUPS, 1000)?;
+
+    if self.groups.len() > max_groups {
+      // Nonfatal, but the user's tokens are missing groups
+      // TODO: telemetry?
+      console_error!(
+        r#"User "{user_email}" is in more than {max_groups} groups, only including the first {max_groups}"#
+      );
+
+      self.groups.truncate(max_groups);
+      self.truncated = true;
+    }
+
+    Ok(())
+  }
+}
+
+/// Returns the names of all groups belonging to the workspace domains that `user_email` is a member of,
+/// mapped by the `GROUP_MAPPING` var.
+/// Note: by default this returns the group email name(s) (<group_name>@<domain>), not the group title.
 /// Google Group titles are not guaranteed unique.
 pub async fn get_user_groups(
   env: &Env,
   user_email: &str
-) -> Result<Vec<String>, HandlerError> {
-  let workspace_domain = get_secret(env, Secret::GOOGLE_WORKSPACE_DOMAIN);
+) -> Result<UserGroups, HandlerError> {
+  let mapping = get_var(env, GROUP_MAPPING, GroupMapping::default())?;
 
-  let client = Client::new();
+  let cached = get_cached_groups(env, user_email).await?;
+
+  // Use the section before the email as the group name.
+  // The `name` parameter itself is not guaranteed unique.
+  let groups = cached.groups.iter()
+    .filter_map(|g| {
+      let Some((name, domain)) = g.email.split_once('@') else {
+        // Nonfatal
+        // TODO: telemetry?
+        console_error!(r#"Invalid group email: "{}""#, g.email);
+        return None
+      };
 
-  let access_token = get_access_token(env, &client).await?;
+      // Only include groups from our workspace domains
+      if !is_workspace_domain(env, domain) {
+        return None
+      }
 
-  // Source: https://developers.google.com/admin-sdk/directory/v1/guides/manage-groups#get_all_member_groups
-  let url = Url::parse_with_params(
-      "https://admin.googleapis.com/admin/directory/v1/groups",
-      &[("userKey", user_email)]
-    )
-    // function doesn't validate params + valid static url
-    .unwrap();
+      let base = mapping.base(name, domain, &g.email, &g.id);
 
-  let GroupsApiResponse { groups } = client.get(url)
-    .bearer_auth(&access_token)
-    .send()
-    .await
-    .map_err(HandlerError::GroupsAdminApi)?
-    .json()
-    .await
-    .map_err(HandlerError::GroupsAdminApi)?;
+      mapping.map(&base)
+    })
+    // several groups can map to the same value
+    .unique()
+    .collect();
 
-  // Use the section before the email as the group name.
-  // The `name` parameter itself is not guaranteed unique.
+  Ok(UserGroups {
+    groups,
+    emails: cached.groups.into_iter().map(|g| g.email).collect(),
+    truncated: cached.truncated,
+    stale: cached.stale
+  })
+}
+
+// ---------- CACHE ----------
+
+/// The user's cached groups if they're fresher than
+/// `GROUPS_CACHE_TTL`, otherwise freshly fetched ones. Falls back to
+/// stale cached groups while the Admin SDK API is failing.
+async fn get_cached_groups(
+  env: &Env,
+  user_email: &str
+) -> Result<CachedGroups, HandlerError> {
+  let kv = KvStore::from_this(env, KV_CACHE)?;
+  let key = cached_groups_key(user_email);
+
+  let ttl = Duration::seconds(get_var(env, GROUPS_CACHE_TTL, 300)?);
+  let max_stale = Duration::seconds(get_var(env, GROUPS_CACHE_MAX_STALE, 86400)?);
+
+  let cached = kv_get::<CachedGroups>(&kv, &key)
+    .await?
+    .filter(|cached| Utc::now() - cached.fetched_at < ttl + max_stale);
+
+  let cached = match cached {
+    Some(cached) if Utc::now() - cached.fetched_at < ttl =>
+      return Ok(cached),
+    // stale or missing
+    cached => cached
+  };
+
+  let (groups, truncated) = match fetch_groups(env, user_email).await {
+    Ok(ok) => ok,
+    Err(e) => match cached {
+      Some(mut cached) => {
+        // Nonfatal
+        // TODO: telemetry?
+        console_error!(
+          r#"Using groups of "{user_email}" cached at {}: {e}"#,
+          cached.fetched_at
+        );
+
+        cached.stale = true;
+        return Ok(cached)
+      },
+      None => return Err(e)
+    }
+  };
+
+  let cached = CachedGroups {
+    groups,
+    truncated,
+    fetched_at: Utc::now(),
+    stale: false
+  };
+
+  // Kept past the TTL as a fallback. KV's shortest TTL is 60 seconds.
+  kv_put(
+    &kv,
+    &key,
+    &cached,
+    (ttl + max_stale).max(Duration::seconds(60))
+  ).await?;
+
+  Ok(cached)
+}
+
+/// Remove a user's cached groups, so their next token has their
+/// current memberships
+pub async fn invalidate_cached_groups(
+  env: &Env,
+  user_email: &str
+) -> Result<(), HandlerError> {
   Ok(
-    groups.into_iter()
-      .filter_map(|g| {
-        let Some((name, domain)) = g.email.split_once('@') else {
-          // Nonfatal
-          // TODO: telemetry?
-          console_error!(r#"Invalid group email: "{}""#, g.email);
-          return None
-        };
-
-        // Only include groups from our workspace domain
-        if domain == workspace_domain {
-          return Some(name.to_string())
+    KvStore::from_this(env, KV_CACHE)?
+      .delete(&cached_groups_key(user_email))
+      .await?
+  )
+}
+
+fn cached_groups_key(user_email: &str) -> String {
+  // email addresses are case insensitive
+  format!("{KEY_CACHED_GROUPS}/{}", user_email.to_lowercase())
+}
+
+// ---------- ADMIN SDK API ----------
+
+/// All groups `user_email` is a member of, fetched from the Admin SDK
+/// API, and whether the nested group walk stopped early
+async fn fetch_groups(
+  env: &Env,
+  user_email: &str
+) -> Result<(Vec<GoogleGroup>, bool), HandlerError> {
+  let client = Client::new();
+
+  let access_token = get_access_token(env, &client, GROUP_SCOPE).await?;
+
+  let max_groups = get_var(env, MAX_GROUPS, 1000)?;
+
+  let mut groups = get_member_groups(&client, &access_token, user_email).await?;
+  let mut truncated = false;
+
+  if get_var(env, NESTED_GROUPS, false)? {
+    // Walk up from the direct memberships. A group is only looked up
+    // the first time it's seen, which also stops membership cycles.
+    let mut seen = groups.iter()
+      .map(|g| g.email.clone())
+      .collect::<BTreeSet<_>>();
+    let mut unvisited = seen.iter().cloned().collect::<Vec<_>>();
+
+    while let Some(group_email) = unvisited.pop() {
+      // Bounds the lookups. Groups can still be filtered out, so the
+      // list is marked incomplete rather than capped here.
+      if groups.len() > max_groups {
+        truncated = true;
+        break
+      }
+
+      for parent in get_member_groups(&client, &access_token, &group_email).await? {
+        if seen.insert(parent.email.clone()) {
+          unvisited.push(parent.email.clone());
+          groups.push(parent);
         }
+      }
+    }
+  }
 
-        None
-      })
-      .collect()
-  )
+  Ok((groups, truncated))
+}
+
+/// Groups `member_key` (a user or group) is a direct member of
+async fn get_member_groups(
+  client: &Client,
+  access_token: &str,
+  member_key: &str
+) -> Result<Vec<GoogleGroup>, HandlerError> {
+  let mut groups = Vec::new();
+  let mut page_token: Option<String> = None;
+
+  // Follow pages until the last one, which has no `nextPageToken`
+  loop {
+    // Source: https://developers.google.com/admin-sdk/directory/v1/guides/manage-groups#get_all_member_groups
+    let mut url = Url::parse_with_params(
+        "https://admin.googleapis.com/admin/directory/v1/groups",
+        &[("userKey", member_key), ("maxResults", "200")]
+      )
+      // function doesn't validate params + valid static url
+      .unwrap();
+
+    if let Some(page_token) = &page_token {
+      url.query_pairs_mut().append_pair("pageToken", page_token);
+    }
+
+    let GroupsApiResponse {
+      groups: page,
+      next_page_token
+    } = client.get(url)
+      .bearer_auth(access_token)
+      .send()
+      .await
+      .map_err(HandlerError::GroupsAdminApi)?
+      // an error body would otherwise parse as no groups
+      .error_for_status()
+      .map_err(HandlerError::GroupsAdminApi)?
+      .json()
+      .await
+      .map_err(HandlerError::GroupsAdminApi)?;
+
+    groups.extend(page);
+
+    match next_page_token {
+      Some(next) => page_token = Some(next),
+      None => return Ok(groups)
+    }
+  }
 }
 
+/// Get a service account token for `scope`. Each scope has its own
+/// token, so the Domain Wide Delegation only needs the scopes of the
+/// features in use.
 /// Source: https://developers.google.com/identity/protocols/oauth2/service-account#httprest
-async fn get_access_token(
+pub async fn get_access_token(
   env: &Env,
-  client: &Client
+  client: &Client,
+  scope: &str
 ) -> Result<String, HandlerError> {
   let admin_email = get_secret(env, Secret::GOOGLE_ADMIN_EMAIL);
 
   let kv = KvStore::from_this(env, KV_CACHE)?;
+  let key = format!("{KEY_SERVICEACCOUNT_OAUTH_TOKEN}/{scope}");
   
-  if let Some(token) = kv_get(&kv, KEY_SERVICEACCOUNT_OAUTH_TOKEN).await? {
+  if let Some(token) = kv_get(&kv, &key).await? {
     return Ok(token)
   }
 
@@ -120,7 +349,7 @@ async fn get_access_token(
     iss: &client_email,
     // admin user the serviceaccount impersonates
     sub: admin_email,
-    scope: "https://www.googleapis.com/auth/admin.directory.group.readonly",
+    scope,
     aud: "https://oauth2.googleapis.com/token",
     exp: (now + token_ttl).timestamp(),
     iat: now.timestamp(),
@@ -130,8 +359,8 @@ async fn get_access_token(
     &Header::new(Algorithm::RS256),
     &claims,
     &EncodingKey::from_rsa_pem(private_key.as_bytes())
-      .map_err(HandlerError::JwtGroupsOauth)?
-  ).map_err(HandlerError::JwtGroupsOauth)?;
+      .map_err(HandlerError::JwtServiceAccountOauth)?
+  ).map_err(HandlerError::JwtServiceAccountOauth)?;
 
   let TokenResponse { access_token } = client
     .post("https://oauth2.googleapis.com/token")
@@ -141,15 +370,15 @@ async fn get_access_token(
     ])
     .send()
     .await
-    .map_err(HandlerError::GroupsOauth)?
+    .map_err(HandlerError::ServiceAccountOauth)?
     .json()
     .await
-    .map_err(HandlerError::GroupsOauth)?;
+    .map_err(HandlerError::ServiceAccountOauth)?;
 
   // cache it
   kv_put(
     &kv,
-    KEY_SERVICEACCOUNT_OAUTH_TOKEN,
+    &key,
     &access_token,
     token_ttl - Duration::minutes(1)
   ).await?;
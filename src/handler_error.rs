@@ -21,6 +21,10 @@ pub enum HandlerError {
   },
   // Parse JSON secret
   JsonDeserialize(#[from] serde_json::Error),
+  #[error(r#"Invalid value for var "{0}""#)]
+  InvalidVar(&'static str),
+  #[error(r#"Missing secret "{0}""#)]
+  MissingSecret(&'static str),
   // OIDC Client
   Discovery(#[from] DiscoveryError<AsyncHttpClientError>),
   ParseUrl(#[from] url::ParseError),
@@ -31,14 +35,29 @@ pub enum HandlerError {
   #[error("Google Oauth response did not include an email claim.")]
   MissingEmailClaim,
   // OIDC Server errors
+  #[error("Invalid pairwise client: {0}")]
+  SectorIdentifier(&'static str),
+  SectorIdentifierUri(reqwest::Error),
+  #[error("Browser binding cookie is missing or does not match the authorize state.")]
+  BrowserBinding,
   Authorize(AuthErrorParams),
   Token(#[from] TokenErrorResponse),
-  // Groups (Google Admin SDK API)
-  GroupsOauth(reqwest::Error),
+  // Google Admin SDK API
+  ServiceAccountOauth(reqwest::Error),
   GroupsAdminApi(reqwest::Error),
+  UsersAdminApi(reqwest::Error),
   // JWT
+  #[error("Invalid signing keys: {0}")]
+  SigningKeys(&'static str),
+  #[error("Failed to generate signing key: {0}")]
+  SigningKeyGeneration(String),
+  #[error("Failed to encrypt or decrypt the stored signing keys.")]
+  SigningKeyEncryption,
   JwtIdToken(JwtError),
-  JwtGroupsOauth(JwtError)
+  JwtAccessToken(JwtError),
+  #[error("Failed to encrypt ID token: {0}")]
+  IdTokenEncryption(&'static str),
+  JwtServiceAccountOauth(JwtError)
 }
 
 /// Convert KvError to StorageError
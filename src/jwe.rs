@@ -0,0 +1,481 @@
+use aes::cipher::{block_padding::Pkcs7, BlockEncryptMut, KeyIvInit};
+use aes_gcm::{aead::{Aead, Payload}, Aes128Gcm, Aes256Gcm, KeyInit};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use p256::ecdh::EphemeralSecret;
+use rsa::{rand_core::{OsRng, RngCore}, BigUint, Oaep, RsaPublicKey};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+
+use crate::{clients::ClientSecret, handler_error::HandlerError};
+
+/// Key management algorithms a client may encrypt its ID tokens with
+/// Source: https://www.rfc-editor.org/rfc/rfc7518#section-4.1
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub enum KeyManagementAlgorithm {
+  #[serde(rename = "RSA-OAEP")]
+  RsaOaep,
+  #[serde(rename = "RSA-OAEP-256")]
+  RsaOaep256,
+  /// Direct key agreement on P-256
+  #[serde(rename = "ECDH-ES")]
+  EcdhEs
+}
+impl KeyManagementAlgorithm {
+  pub const ALL: [KeyManagementAlgorithm; 3] = [
+    KeyManagementAlgorithm::RsaOaep,
+    KeyManagementAlgorithm::RsaOaep256,
+    KeyManagementAlgorithm::EcdhEs
+  ];
+
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      KeyManagementAlgorithm::RsaOaep => "RSA-OAEP",
+      KeyManagementAlgorithm::RsaOaep256 => "RSA-OAEP-256",
+      KeyManagementAlgorithm::EcdhEs => "ECDH-ES"
+    }
+  }
+
+  fn kty(&self) -> &'static str {
+    match self {
+      KeyManagementAlgorithm::RsaOaep | KeyManagementAlgorithm::RsaOaep256 => "RSA",
+      KeyManagementAlgorithm::EcdhEs => "EC"
+    }
+  }
+}
+
+/// Content encryption algorithms a client may encrypt its ID tokens with
+/// Source: https://www.rfc-editor.org/rfc/rfc7518#section-5.1
+#[derive(Serialize, Deserialize, Clone, Copy, Default)]
+pub enum ContentEncryption {
+  /// Default when a client sets only `id_token_encrypted_response_alg`
+  /// Source: https://openid.net/specs/openid-connect-registration-1_0.html#ClientMetadata
+  #[default]
+  #[serde(rename = "A128CBC-HS256")]
+  A128CbcHs256,
+  A128GCM,
+  A256GCM
+}
+impl ContentEncryption {
+  pub const ALL: [ContentEncryption; 3] = [
+    ContentEncryption::A128CbcHs256,
+    ContentEncryption::A128GCM,
+    ContentEncryption::A256GCM
+  ];
+
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      ContentEncryption::A128CbcHs256 => "A128CBC-HS256",
+      ContentEncryption::A128GCM => "A128GCM",
+      ContentEncryption::A256GCM => "A256GCM"
+    }
+  }
+
+  /// Content encryption key length in bytes
+  fn key_len(&self) -> usize {
+    match self {
+      ContentEncryption::A128CbcHs256 | ContentEncryption::A256GCM => 32,
+      ContentEncryption::A128GCM => 16
+    }
+  }
+
+  /// Initialization vector length in bytes
+  fn iv_len(&self) -> usize {
+    match self {
+      ContentEncryption::A128CbcHs256 => 16,
+      ContentEncryption::A128GCM | ContentEncryption::A256GCM => 12
+    }
+  }
+}
+
+/// Public keys registered by a client
+/// Source: https://openid.net/specs/openid-connect-registration-1_0.html#ClientMetadata (jwks)
+#[derive(Deserialize)]
+pub struct ClientJwks {
+  pub keys: Vec<Map<String, Value>>
+}
+
+#[derive(Serialize)]
+struct JweHeader<'a> {
+  alg: &'static str,
+  enc: &'static str,
+  /// The payload is a signed JWT
+  /// Source: https://openid.net/specs/openid-connect-core-1_0.html#SigningOrder
+  cty: &'static str,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  kid: Option<&'a str>,
+  /// Ephemeral public key for ECDH-ES
+  #[serde(skip_serializing_if = "Option::is_none")]
+  epk: Option<Value>
+}
+
+/// Encrypt the signed ID token `jwt` to `client`'s registered key, if
+/// it asked for encrypted ID tokens. Returns a nested JWT in JWE
+/// compact serialization.
+/// Source: https://www.rfc-editor.org/rfc/rfc7516#section-5.1
+pub fn encrypt_id_token(
+  client: &ClientSecret,
+  jwt: String
+) -> Result<String, HandlerError> {
+  let Some(alg) = client.id_token_encrypted_response_alg else {
+    return Ok(jwt)
+  };
+  let enc = client.id_token_encrypted_response_enc.unwrap_or_default();
+
+  let jwk = client.jwks
+    .as_ref()
+    .and_then(|jwks| jwks.keys.iter().find(|jwk| is_encryption_key(jwk, alg)))
+    .ok_or(HandlerError::IdTokenEncryption("client has no matching encryption key"))?;
+
+  let mut header = JweHeader {
+    alg: alg.as_str(),
+    enc: enc.as_str(),
+    cty: "JWT",
+    kid: jwk.get("kid").and_then(Value::as_str),
+    epk: None
+  };
+
+  let (cek, encrypted_key) = match alg {
+    KeyManagementAlgorithm::RsaOaep | KeyManagementAlgorithm::RsaOaep256 => {
+      let cek = random_bytes(enc.key_len());
+      let encrypted_key = rsa_encrypt(jwk, alg, &cek)?;
+      (cek, encrypted_key)
+    }
+    KeyManagementAlgorithm::EcdhEs => {
+      let (cek, epk) = ecdh_es_agree(jwk, enc)?;
+      header.epk = Some(epk);
+      // the agreed key is used directly
+      (cek, Vec::new())
+    }
+  };
+
+  // the encoded protected header is the additional authenticated data
+  let protected = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&header)?);
+
+  let content = encrypt_content(
+    enc,
+    &cek,
+    protected.as_bytes(),
+    jwt.as_bytes()
+  )?;
+
+  Ok([
+    protected,
+    URL_SAFE_NO_PAD.encode(encrypted_key),
+    URL_SAFE_NO_PAD.encode(content.iv),
+    URL_SAFE_NO_PAD.encode(content.ciphertext),
+    URL_SAFE_NO_PAD.encode(content.tag)
+  ].join("."))
+}
+
+/// Source: https://www.rfc-editor.org/rfc/rfc7517#section-4.2
+fn is_encryption_key(jwk: &Map<String, Value>, alg: KeyManagementAlgorithm) -> bool {
+  let kty = jwk.get("kty").and_then(Value::as_str);
+  let key_use = jwk.get("use").and_then(Value::as_str);
+
+  kty == Some(alg.kty()) && key_use.is_none_or(|key_use| key_use == "enc")
+}
+
+/// Source: https://www.rfc-editor.org/rfc/rfc7518#section-4.3
+fn rsa_encrypt(
+  jwk: &Map<String, Value>,
+  alg: KeyManagementAlgorithm,
+  cek: &[u8]
+) -> Result<Vec<u8>, HandlerError> {
+  let param = |name| jwk.get(name)
+    .and_then(Value::as_str)
+    .and_then(|value| URL_SAFE_NO_PAD.decode(value).ok())
+    .map(|bytes| BigUint::from_bytes_be(&bytes))
+    .ok_or(HandlerError::IdTokenEncryption("invalid RSA encryption key"));
+
+  let key = RsaPublicKey::new(param("n")?, param("e")?)
+    .map_err(|_| HandlerError::IdTokenEncryption("invalid RSA encryption key"))?;
+
+  let padding = match alg {
+    KeyManagementAlgorithm::RsaOaep256 => Oaep::new::<Sha256>(),
+    _ => Oaep::new::<Sha1>()
+  };
+
+  key.encrypt(&mut OsRng, padding, cek)
+    .map_err(|_| HandlerError::IdTokenEncryption("RSA encryption failed"))
+}
+
+/// Agree on a content encryption key with an ephemeral key pair.
+/// Returns the key and the ephemeral public JWK.
+/// Source: https://www.rfc-editor.org/rfc/rfc7518#section-4.6
+fn ecdh_es_agree(
+  jwk: &Map<String, Value>,
+  enc: ContentEncryption
+) -> Result<(Vec<u8>, Value), HandlerError> {
+  // p256 rejects other members, like `kid` and `use`
+  let public_jwk = jwk.iter()
+    .filter(|(name, _)| matches!(name.as_str(), "kty" | "crv" | "x" | "y"))
+    .map(|(name, value)| (name.clone(), value.clone()))
+    .collect::<Map<_, _>>();
+
+  let public = p256::PublicKey::from_jwk_str(&serde_json::to_string(&public_jwk)?)
+    .map_err(|_| HandlerError::IdTokenEncryption("invalid EC encryption key"))?;
+
+  let ephemeral = EphemeralSecret::random(&mut OsRng);
+  let shared = ephemeral.diffie_hellman(&public);
+
+  // no PartyUInfo or PartyVInfo
+  let cek = concat_kdf(
+    shared.raw_secret_bytes(),
+    enc.as_str().as_bytes(),
+    &[],
+    &[],
+    enc.key_len()
+  );
+
+  let epk = serde_json::to_value(ephemeral.public_key().to_jwk())?;
+
+  Ok((cek, epk))
+}
+
+/// Derive a `key_len` byte key from the ECDH shared secret. Every key
+/// is at most 256 bits, so one round of SHA-256 is enough.
+/// Source: https://www.rfc-editor.org/rfc/rfc7518#section-4.6.2
+fn concat_kdf(
+  shared: &[u8],
+  algorithm_id: &[u8],
+  party_u_info: &[u8],
+  party_v_info: &[u8],
+  key_len: usize
+) -> Vec<u8> {
+  let digest = Sha256::new()
+    .chain_update(1u32.to_be_bytes())
+    .chain_update(shared)
+    .chain_update((algorithm_id.len() as u32).to_be_bytes())
+    .chain_update(algorithm_id)
+    .chain_update((party_u_info.len() as u32).to_be_bytes())
+    .chain_update(party_u_info)
+    .chain_update((party_v_info.len() as u32).to_be_bytes())
+    .chain_update(party_v_info)
+    .chain_update((key_len as u32 * 8).to_be_bytes())
+    .finalize();
+
+  digest[..key_len].to_vec()
+}
+
+struct EncryptedContent {
+  iv: Vec<u8>,
+  ciphertext: Vec<u8>,
+  tag: Vec<u8>
+}
+
+/// Encrypt `plaintext` with a random IV
+fn encrypt_content(
+  enc: ContentEncryption,
+  cek: &[u8],
+  aad: &[u8],
+  plaintext: &[u8]
+) -> Result<EncryptedContent, HandlerError> {
+  encrypt_content_with_iv(enc, cek, random_bytes(enc.iv_len()), aad, plaintext)
+}
+
+fn encrypt_content_with_iv(
+  enc: ContentEncryption,
+  cek: &[u8],
+  iv: Vec<u8>,
+  aad: &[u8],
+  plaintext: &[u8]
+) -> Result<EncryptedContent, HandlerError> {
+  match enc {
+    // Source: https://www.rfc-editor.org/rfc/rfc7518#section-5.2.2.1
+    ContentEncryption::A128CbcHs256 => {
+      let (mac_key, enc_key) = cek.split_at(16);
+
+      let ciphertext = cbc::Encryptor::<aes::Aes128>::new_from_slices(enc_key, &iv)
+        .map_err(|_| HandlerError::IdTokenEncryption("invalid content encryption key"))?
+        .encrypt_padded_vec_mut::<Pkcs7>(plaintext);
+
+      let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(mac_key)
+        // HMAC accepts keys of any length
+        .unwrap();
+      mac.update(aad);
+      mac.update(&iv);
+      mac.update(&ciphertext);
+      mac.update(&(aad.len() as u64 * 8).to_be_bytes());
+      let tag = mac.finalize().into_bytes()[..16].to_vec();
+
+      Ok(EncryptedContent { iv, ciphertext, tag })
+    }
+    // Source: https://www.rfc-editor.org/rfc/rfc7518#section-5.3
+    ContentEncryption::A128GCM | ContentEncryption::A256GCM => {
+      let payload = Payload { msg: plaintext, aad };
+
+      let mut sealed = match enc {
+        ContentEncryption::A128GCM => Aes128Gcm::new_from_slice(cek)
+          .map(|cipher| cipher.encrypt(iv.as_slice().into(), payload)),
+        _ => Aes256Gcm::new_from_slice(cek)
+          .map(|cipher| cipher.encrypt(iv.as_slice().into(), payload))
+      }
+        .map_err(|_| HandlerError::IdTokenEncryption("invalid content encryption key"))?
+        .map_err(|_| HandlerError::IdTokenEncryption("AES-GCM encryption failed"))?;
+
+      // aes-gcm appends the 16 byte tag to the ciphertext
+      let tag = sealed.split_off(sealed.len() - 16);
+
+      Ok(EncryptedContent { iv, ciphertext: sealed, tag })
+    }
+  }
+}
+
+fn random_bytes(len: usize) -> Vec<u8> {
+  let mut bytes = vec![0u8; len];
+  OsRng.fill_bytes(&mut bytes);
+  bytes
+}
+
+#[cfg(test)]
+mod tests {
+  use p256::{ecdh::diffie_hellman, PublicKey, SecretKey};
+  use rsa::{traits::PublicKeyParts, RsaPrivateKey};
+  use serde_json::json;
+
+  use super::*;
+
+  fn hex(s: &str) -> Vec<u8> {
+    (0..s.len())
+      .step_by(2)
+      .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+      .collect()
+  }
+
+  // Bob's key from RFC 7518 Appendix C
+  const EC_JWK: &str = r#"{"kty":"EC","crv":"P-256","x":"weNJy2HscCSM6AEDTDg04biOvhFhyyWvOHQfeF_PxMQ","y":"e8lnCO-AlStT-NJVX-crhB7QRYhiix03illJOVAOyck","d":"VEmDZpDXXK8p8N0Cndsxs924q6nS1RXFASRl6BfUqdw"}"#;
+
+  // Source: https://www.rfc-editor.org/rfc/rfc7518#appendix-B.1
+  #[test]
+  fn a128cbc_hs256_rfc7518_b1() {
+    let content = encrypt_content_with_iv(
+      ContentEncryption::A128CbcHs256,
+      &(0..32).collect::<Vec<u8>>(),
+      hex("1af38c2dc2b96ffdd86694092341bc04"),
+      b"The second principle of Auguste Kerckhoffs",
+      b"A cipher system must not be required to be secret, and it must be able to fall into the hands of the enemy without inconvenience"
+    ).unwrap();
+
+    assert_eq!(content.ciphertext, hex(concat!(
+      "c80edfa32ddf39d5ef00c0b468834279a2e46a1b8049f792f76bfe54b903a9c9",
+      "a94ac9b47ad2655c5f10f9aef71427e2fc6f9b3f399a221489f16362c7032336",
+      "09d45ac69864e3321cf82935ac4096c86e133314c54019e8ca7980dfa4b9cf1b",
+      "384c486f3a54c51078158ee5d79de59fbd34d848b3d69550a67646344427ade5",
+      "4b8851ffb598f7f80074b9473c82e2db"
+    )));
+    assert_eq!(content.tag, hex("652c3fa36b0a7c5b3219fab3a30bc1c4"));
+  }
+
+  // Source: https://www.rfc-editor.org/rfc/rfc7516#appendix-A.3
+  #[test]
+  fn a128cbc_hs256_rfc7516_a3() {
+    let content = encrypt_content_with_iv(
+      ContentEncryption::A128CbcHs256,
+      &[4, 211, 31, 197, 84, 157, 252, 254, 11, 100, 157, 250, 63, 170, 106, 206, 107, 124, 212, 45, 111, 107, 9, 219, 200, 177, 0, 240, 143, 156, 44, 207],
+      vec![3, 22, 60, 12, 43, 67, 104, 105, 108, 108, 105, 99, 111, 116, 104, 101],
+      b"eyJhbGciOiJBMTI4S1ciLCJlbmMiOiJBMTI4Q0JDLUhTMjU2In0",
+      b"Live long and prosper."
+    ).unwrap();
+
+    assert_eq!(URL_SAFE_NO_PAD.encode(content.ciphertext), "KDlTtXchhZTGufMYmOYGS4HffxPSUrfmqCHXaI9wOGY");
+    assert_eq!(URL_SAFE_NO_PAD.encode(content.tag), "U0m_YmjN04DJvceFICbCVQ");
+  }
+
+  // Source: https://www.rfc-editor.org/rfc/rfc7516#appendix-A.1
+  #[test]
+  fn a256gcm_rfc7516_a1() {
+    let content = encrypt_content_with_iv(
+      ContentEncryption::A256GCM,
+      &[177, 161, 244, 128, 84, 143, 225, 115, 63, 180, 3, 255, 107, 154, 212, 246, 138, 7, 110, 91, 112, 46, 34, 105, 47, 130, 203, 46, 122, 234, 64, 252],
+      vec![227, 197, 117, 252, 2, 219, 233, 68, 180, 225, 77, 219],
+      b"eyJhbGciOiJSU0EtT0FFUCIsImVuYyI6IkEyNTZHQ00ifQ",
+      b"The true sign of intelligence is not knowledge but imagination."
+    ).unwrap();
+
+    assert_eq!(
+      URL_SAFE_NO_PAD.encode(content.ciphertext),
+      "5eym8TW_c8SuK0ltJ3rpYIzOeDQz7TALvtu6UG9oMo4vpzs9tX_EFShS8iB7j6jiSdiwkIr3ajwQzaBtQD_A"
+    );
+    assert_eq!(URL_SAFE_NO_PAD.encode(content.tag), "XFBoMYUZodetZdvTiFvSkQ");
+  }
+
+  // Source: https://www.rfc-editor.org/rfc/rfc7518#appendix-C
+  #[test]
+  fn concat_kdf_rfc7518_c() {
+    let shared = [158, 86, 217, 29, 129, 113, 53, 211, 114, 131, 66, 131, 191, 132, 38, 156, 251, 49, 110, 163, 218, 128, 106, 72, 246, 218, 167, 121, 140, 254, 144, 196];
+
+    let key = concat_kdf(&shared, b"A128GCM", b"Alice", b"Bob", 16);
+
+    assert_eq!(URL_SAFE_NO_PAD.encode(key), "VqqN6vgjbSBcIijNcacQGg");
+  }
+
+  #[test]
+  fn rsa_oaep_round_trip() {
+    let private = RsaPrivateKey::new(&mut OsRng, 2048).unwrap();
+    let jwk = json!({
+      "kty": "RSA",
+      "n": URL_SAFE_NO_PAD.encode(private.n().to_bytes_be()),
+      "e": URL_SAFE_NO_PAD.encode(private.e().to_bytes_be())
+    });
+    let cek = random_bytes(32);
+
+    for (alg, padding) in [
+      (KeyManagementAlgorithm::RsaOaep, Oaep::new::<Sha1>()),
+      (KeyManagementAlgorithm::RsaOaep256, Oaep::new::<Sha256>())
+    ] {
+      let encrypted_key = rsa_encrypt(jwk.as_object().unwrap(), alg, &cek).unwrap();
+
+      assert_eq!(private.decrypt(padding, &encrypted_key).unwrap(), cek);
+    }
+  }
+
+  #[test]
+  fn ecdh_es_a128gcm_round_trip() {
+    let private = SecretKey::from_jwk_str(EC_JWK).unwrap();
+    let client: ClientSecret = serde_json::from_value(json!({
+      "redirect_uris": [],
+      "id_token_encrypted_response_alg": "ECDH-ES",
+      "id_token_encrypted_response_enc": "A128GCM",
+      "jwks": {"keys": [{
+        "kty": "EC",
+        "crv": "P-256",
+        "kid": "bob",
+        "use": "enc",
+        "x": "weNJy2HscCSM6AEDTDg04biOvhFhyyWvOHQfeF_PxMQ",
+        "y": "e8lnCO-AlStT-NJVX-crhB7QRYhiix03illJOVAOyck"
+      }]}
+    })).unwrap();
+
+    let jwe = encrypt_id_token(&client, "header.payload.signature".into()).unwrap();
+
+    let [protected, encrypted_key, iv, ciphertext, tag] = jwe.split('.')
+      .map(|part| URL_SAFE_NO_PAD.decode(part).unwrap())
+      .collect::<Vec<_>>()
+      .try_into()
+      .unwrap();
+    assert!(encrypted_key.is_empty());
+
+    let header: Value = serde_json::from_slice(&protected).unwrap();
+    assert_eq!(header["alg"], "ECDH-ES");
+    assert_eq!(header["enc"], "A128GCM");
+    assert_eq!(header["cty"], "JWT");
+    assert_eq!(header["kid"], "bob");
+
+    let epk = PublicKey::from_jwk_str(&header["epk"].to_string()).unwrap();
+    let shared = diffie_hellman(private.to_nonzero_scalar(), epk.as_affine());
+    let cek = concat_kdf(shared.raw_secret_bytes(), b"A128GCM", &[], &[], 16);
+
+    let plaintext = Aes128Gcm::new_from_slice(&cek)
+      .unwrap()
+      .decrypt(iv.as_slice().into(), Payload {
+        msg: &[ciphertext, tag].concat(),
+        aad: jwe.split('.').next().unwrap().as_bytes()
+      })
+      .unwrap();
+
+    assert_eq!(plaintext, b"header.payload.signature");
+  }
+}
@@ -1,17 +1,29 @@
+mod authentication;
+mod browser_binding;
+mod claims;
+mod clients;
 mod consts;
 mod endpoints;
 mod handler_error;
+mod jwe;
 mod google;
+mod group_mapping;
 mod groups;
 mod oidc_token;
+mod resource;
+mod roles;
 mod scope;
+mod signing_keys;
+mod subject;
 mod state;
+mod users;
 
 use consts::validate_secrets;
-use endpoints::{authorize, callback, jwks, token, well_known};
-use axum::{body::Body, http::Response, routing::{get, post}, Router};
+use endpoints::{authorize, callback, invalidate_groups, jwks, rotate_keys, token, userinfo, well_known};
+use axum::{body::Body, http::Response, routing::{delete, get, post}, Router};
+use signing_keys::rotate_signing_keys;
 use tower_service::Service;
-use worker::{event, Context, Env, HttpRequest};
+use worker::{console_error, event, Context, Env, HttpRequest, ScheduleContext, ScheduledEvent};
 
 // Program entrypoint. Essentially the `main` function.
 #[event(fetch)]
@@ -26,12 +38,31 @@ async fn fetch(req: HttpRequest, env: Env, _: Context) -> worker::Result<Respons
     Router::new()
       // Source: https://openid.net/specs/openid-connect-discovery-1_0.html#ProviderConfig
       .route("/.well-known/openid-configuration", get(well_known::openid_configuration))
+      // Source: https://www.rfc-editor.org/rfc/rfc8414.html#section-3
+      .route("/.well-known/oauth-authorization-server", get(well_known::oauth_authorization_server))
       .route("/authorize", get(authorize).post(authorize))
       .route("/callback", get(callback))
       .route("/token", post(token))
+      .route("/userinfo", get(userinfo).post(userinfo))
       .route("/jwks", get(jwks))
+      .route("/admin/groups/:user_email", delete(invalidate_groups))
+      .route("/admin/signing-keys/rotate", post(rotate_keys))
       .with_state(env)
       .call(req)
       .await?
   )
+}
+
+// Cron Trigger entrypoint. Schedules are set in wrangler.toml.
+#[event(scheduled)]
+async fn scheduled(_: ScheduledEvent, env: Env, _: ScheduleContext) {
+  console_error_panic_hook::set_once();
+
+  // panic on missing secrets
+  validate_secrets(&env);
+
+  if let Err(e) = rotate_signing_keys(&env, false).await {
+    // TODO: telemetry?
+    console_error!("{e}");
+  }
 }
\ No newline at end of file
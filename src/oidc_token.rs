@@ -1,52 +1,183 @@
 use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
-use chrono::{DateTime, Utc};
+use chrono::Utc;
 use getrandom::getrandom;
 use openidconnect::SubjectIdentifier;
 use serde::Serialize;
-use surrealdb_jsonwebtoken::{encode, Algorithm, EncodingKey, Header};
+use serde_json::Value;
+use sha2::{Digest, Sha256, Sha512};
+use surrealdb_jsonwebtoken::{encode, Header};
 use worker::Env;
 
-use crate::{consts::{get_secret, Secret}, handler_error::HandlerError};
+use crate::{claims::UserClaims, clients::ClientSecret, consts::{get_secret, Secret}, handler_error::HandlerError, jwe::encrypt_id_token, signing_keys::{active_signing_key, client_signing_algorithm, get_signing_keys, SigningAlgorithm}, state::CommonTokenState};
 
-// Source: https://openid.net/specs/openid-connect-core-1_0.html#IDToken
+/// A single audience is serialized as a string
+#[derive(Serialize)]
+#[serde(untagged)]
+enum Audience<'a> {
+  Single(&'a str),
+  Multiple(Vec<&'a str>)
+}
+
+// Sources:
+//   https://openid.net/specs/openid-connect-core-1_0.html#IDToken
+//   https://openid.net/specs/openid-connect-core-1_0.html#CodeIDToken
+//   https://www.rfc-editor.org/rfc/rfc7519#section-4.1.7
 #[derive(Serialize)]
 struct OidcToken<'a> {
   iss: &'a str,
-  aud: &'a str,
-  sub: SubjectIdentifier,
+  aud: Audience<'a>,
+  azp: &'a str,
   exp: u64,
   iat: u64,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  auth_time: Option<u64>,
+  // Source: https://openid.net/specs/openid-connect-core-1_0.html#IDToken (acr, amr)
+  #[serde(skip_serializing_if = "Option::is_none")]
+  acr: Option<&'a str>,
+  #[serde(skip_serializing_if = "<[String]>::is_empty")]
+  amr: &'a [String],
+  jti: String,
   nonce: &'a str,
-  groups: Option<Vec<String>>
+  at_hash: String,
+  // `sub` and the scope claims
+  #[serde(flatten)]
+  claims: &'a UserClaims
 }
-pub fn create_oidc_token(
+pub async fn create_oidc_token(
   env: &Env,
-  client_id: &str,
-  client_nonce: &str,
-  google_subject: SubjectIdentifier,
-  issue_time: DateTime<Utc>,
-  expiration: DateTime<Utc>,
-  groups: Option<Vec<String>>
+  client: &ClientSecret,
+  common: &CommonTokenState,
+  claims: &UserClaims,
+  access_token: &str
 ) -> Result<String, HandlerError> {
+  let signing_keys = get_signing_keys(env).await?;
+  let signing_key = active_signing_key(
+    &signing_keys,
+    client_signing_algorithm(env, client)?
+  )?;
+
+  let now = Utc::now();
+
   let oidc_token = OidcToken {
     iss: get_secret(env, Secret::WORKER_DOMAIN),
-    aud: client_id,
-    sub: google_subject,
+    aud: Audience::Single(&common.client_id),
+    // Source: https://openid.net/specs/openid-connect-core-1_0.html#IDToken (azp)
+    azp: &common.client_id,
     // panic if these are negative
-    iat: issue_time.timestamp().try_into().unwrap(),
-    exp: expiration.timestamp().try_into().unwrap(),
-    nonce: client_nonce,
-    groups
+    iat: now.timestamp().try_into().unwrap(),
+    exp: (now + client.id_token_lifetime(env)?).timestamp().try_into().unwrap(),
+    auth_time: common.auth_time.map(|t| t.timestamp().try_into().unwrap()),
+    acr: common.acr.as_deref(),
+    amr: &common.amr,
+    jti: new_token::<16>(),
+    nonce: &common.client_nonce,
+    at_hash: token_hash(signing_key.alg, access_token),
+    claims
   };
 
+  // tell clients which of the published keys to verify with
+  let mut header = Header::new(signing_key.alg.jwt_algorithm());
+  header.kid = Some(signing_key.kid().to_string());
+
   // JWT encode it
-  encode(
-    &Header::new(Algorithm::RS256),
+  let jwt = encode(
+    &header,
     &oidc_token,
-    &EncodingKey::from_rsa_pem(
-      get_secret(env, Secret::JWK_PRIVATE).as_bytes()
-    ).map_err(HandlerError::JwtIdToken)?
-  ).map_err(HandlerError::JwtIdToken)
+    &signing_key.encoding_key()?
+  ).map_err(HandlerError::JwtIdToken)?;
+
+  encrypt_id_token(client, jwt)
+}
+
+/// Source: https://www.rfc-editor.org/rfc/rfc9068#section-2.2
+#[derive(Serialize)]
+struct JwtAccessToken<'a> {
+  iss: &'a str,
+  aud: Audience<'a>,
+  sub: &'a SubjectIdentifier,
+  client_id: &'a str,
+  exp: u64,
+  iat: u64,
+  jti: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  auth_time: Option<u64>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  acr: Option<&'a str>,
+  #[serde(skip_serializing_if = "<[String]>::is_empty")]
+  amr: &'a [String],
+  #[serde(skip_serializing_if = "String::is_empty")]
+  scope: String,
+  // Source: https://www.rfc-editor.org/rfc/rfc9068#section-2.2.3.1
+  #[serde(skip_serializing_if = "Option::is_none")]
+  groups: Option<&'a Value>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  roles: Option<&'a Value>
+}
+/// Access tokens for `resources` are JWTs the resource servers can
+/// verify themselves. Without any, the access token is only accepted
+/// by `/userinfo`, so it's an opaque random value.
+pub async fn create_access_token(
+  env: &Env,
+  client: &ClientSecret,
+  common: &CommonTokenState,
+  claims: &UserClaims,
+  resources: &[String]
+) -> Result<String, HandlerError> {
+  let aud = match resources {
+    [] => return Ok(new_token::<16>()),
+    [resource] => Audience::Single(resource),
+    _ => Audience::Multiple(resources.iter().map(String::as_str).collect())
+  };
+
+  let signing_keys = get_signing_keys(env).await?;
+  let signing_key = active_signing_key(
+    &signing_keys,
+    client_signing_algorithm(env, client)?
+  )?;
+
+  let now = Utc::now();
+
+  let access_token = JwtAccessToken {
+    iss: get_secret(env, Secret::WORKER_DOMAIN),
+    aud,
+    sub: &claims.sub,
+    client_id: &common.client_id,
+    // panic if these are negative
+    iat: now.timestamp().try_into().unwrap(),
+    exp: (now + client.access_token_lifetime(env)?).timestamp().try_into().unwrap(),
+    jti: new_token::<16>(),
+    auth_time: common.auth_time.map(|t| t.timestamp().try_into().unwrap()),
+    acr: common.acr.as_deref(),
+    amr: &common.amr,
+    scope: common.scopes.join(" "),
+    groups: claims.claims.get("groups"),
+    roles: claims.claims.get("roles")
+  };
+
+  // Source: https://www.rfc-editor.org/rfc/rfc9068#section-2.1
+  let mut header = Header::new(signing_key.alg.jwt_algorithm());
+  header.typ = Some("at+jwt".into());
+  header.kid = Some(signing_key.kid().to_string());
+
+  encode(
+    &header,
+    &access_token,
+    &signing_key.encoding_key()?
+  ).map_err(HandlerError::JwtAccessToken)
+}
+
+/// base64url encoded left half of the hash of `value`, using the hash
+/// function of the signing algorithm.
+/// Source: https://openid.net/specs/openid-connect-core-1_0.html#CodeIDToken
+fn token_hash(alg: SigningAlgorithm, value: &str) -> String {
+  let digest = match alg {
+    SigningAlgorithm::RS256 | SigningAlgorithm::ES256 =>
+      Sha256::digest(value).to_vec(),
+    // Ed25519 signs with SHA-512
+    SigningAlgorithm::EdDSA => Sha512::digest(value).to_vec()
+  };
+
+  URL_SAFE_NO_PAD.encode(&digest[..digest.len() / 2])
 }
 
 pub fn new_token<const BYTES: usize>() -> String {
@@ -0,0 +1,81 @@
+use openidconnect::core::{CoreAuthErrorResponseType, CoreErrorResponseType};
+use serde::de::DeserializeOwned;
+use url::Url;
+
+use crate::{clients::ClientSecret, endpoints::{authorize_error, token_error}, handler_error::HandlerError};
+
+/// Source: https://www.rfc-editor.org/rfc/rfc8707#section-2
+const INVALID_TARGET: &str = "invalid_target";
+
+/// Deserialize form params. `resource` may be repeated, which
+/// `serde_urlencoded` doesn't support, so its values are returned
+/// separately.
+pub fn parse_form<T: DeserializeOwned>(
+  form: &[u8]
+) -> Result<(T, Vec<String>), serde_urlencoded::de::Error> {
+  let (resources, params): (Vec<_>, Vec<_>) = form_urlencoded::parse(form)
+    .partition(|(name, _)| name == "resource");
+
+  let params = serde_urlencoded::from_str(
+    &form_urlencoded::Serializer::new(String::new())
+      .extend_pairs(params)
+      .finish()
+  )?;
+
+  Ok((
+    params,
+    resources.into_iter()
+      .map(|(_, resource)| resource.into_owned())
+      .collect()
+  ))
+}
+
+/// Check the resources requested at `/authorize` against the ones
+/// registered to the client. The resources are kept as requested, they
+/// become the `aud` of access tokens.
+pub fn authorize_resources(
+  client: &ClientSecret,
+  requested: &[String]
+) -> Result<Vec<String>, HandlerError> {
+  requested.iter()
+    .map(|resource| match parse_resource(resource) {
+      Some(url) if client.resources.contains(&url) => Ok(resource.clone()),
+      _ => authorize_error::error(
+        CoreAuthErrorResponseType::Extension(INVALID_TARGET.into()),
+        format!(r#"Resource "{resource}" is not allowed for this client"#).into()
+      )
+    })
+    .collect()
+}
+
+/// Resources the access token is issued for. They must have been
+/// granted at `/authorize`, and default to all of them.
+/// Source: https://www.rfc-editor.org/rfc/rfc8707#section-2.2
+pub fn token_resources(
+  granted: &[String],
+  requested: &[String]
+) -> Result<Vec<String>, HandlerError> {
+  if requested.is_empty() {
+    return Ok(granted.to_vec())
+  }
+
+  requested.iter()
+    .map(|resource| match parse_resource(resource) {
+      Some(url) if granted.iter().any(|granted| parse_resource(granted).as_ref() == Some(&url)) =>
+        Ok(resource.clone()),
+      _ => token_error::error(
+        CoreErrorResponseType::Extension(INVALID_TARGET.into()),
+        Some(format!(r#"Resource "{resource}" was not granted"#))
+      )
+    })
+    .collect()
+}
+
+/// Resources are absolute URIs without a fragment. The parsed URL is
+/// only used to validate and compare them.
+/// Source: https://www.rfc-editor.org/rfc/rfc8707#section-2
+fn parse_resource(resource: &str) -> Option<Url> {
+  Url::parse(resource)
+    .ok()
+    .filter(|url| url.fragment().is_none())
+}
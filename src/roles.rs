@@ -0,0 +1,65 @@
+use std::{collections::{BTreeMap, BTreeSet}, str::FromStr};
+
+use serde::Deserialize;
+use worker::Env;
+
+use crate::{clients::glob_match, consts::{get_var, ROLE_MAPPING}, handler_error::HandlerError};
+
+/// JSON map from a role to the groups that grant it, from the
+/// `ROLE_MAPPING` var, e.g.
+/// `{"admin": {"groups": ["k8s-admins"], "inherits": ["viewer"]}, "viewer": {"groups": ["k8s-*"]}}`
+#[derive(Default)]
+struct RoleMapping(BTreeMap<String, RoleDefinition>);
+impl FromStr for RoleMapping {
+  type Err = serde_json::Error;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    serde_json::from_str(s).map(RoleMapping)
+  }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RoleDefinition {
+  /// Patterns of the groups granting the role, where `*` matches any
+  /// characters
+  #[serde(default)]
+  groups: Vec<String>,
+  /// Roles users with this role also have
+  #[serde(default)]
+  inherits: Vec<String>
+}
+
+/// The roles a user in `groups` has, including inherited roles.
+/// `groups` are the names after `GROUP_MAPPING`.
+pub fn user_roles(env: &Env, groups: &[String]) -> Result<Vec<String>, HandlerError> {
+  let RoleMapping(mapping) = get_var(env, ROLE_MAPPING, RoleMapping::default())?;
+
+  let unknown_role = mapping.values()
+    .flat_map(|definition| &definition.inherits)
+    .any(|role| !mapping.contains_key(role));
+
+  if unknown_role {
+    return Err(HandlerError::InvalidVar(ROLE_MAPPING))
+  }
+
+  let mut roles = BTreeSet::new();
+
+  // roles granted directly by a group
+  let mut unvisited = mapping.iter()
+    .filter(|(_, definition)| definition.groups.iter()
+      .any(|pattern| groups.iter().any(|group| glob_match(pattern, group)))
+    )
+    .map(|(role, _)| role)
+    .collect::<Vec<_>>();
+
+  // Add inherited roles. A role is only visited the first time it's
+  // added, which also stops inheritance cycles.
+  while let Some(role) = unvisited.pop() {
+    if roles.insert(role.clone()) {
+      unvisited.extend(&mapping[role].inherits);
+    }
+  }
+
+  Ok(roles.into_iter().collect())
+}
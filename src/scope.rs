@@ -1,7 +1,10 @@
+use std::{collections::BTreeMap, str::FromStr};
+
 use itertools::Itertools;
 use openidconnect::core::CoreAuthErrorResponseType;
+use worker::Env;
 
-use crate::{endpoints::authorize_error::error, handler_error::HandlerError};
+use crate::{claims::claim_names, consts::{get_var, CUSTOM_SCOPES}, endpoints::authorize_error::error, handler_error::HandlerError};
 
 /// Adapted from https://github.com/HeroicKatora/oxide-auth/blob/019b7651e97a3ee2cde40b9806a1ecf37e051942/oxide-auth/src/primitives/scope.rs#L120
 pub fn parse_scopes(scopes: &str) -> Result<impl Iterator<Item = &str>, HandlerError> {
@@ -30,4 +33,64 @@ fn invalid_scope_char(ch: char) -> bool {
       ' ' => false, // Space separator is a valid char
       _ => true,
   }
-}
\ No newline at end of file
+}
+
+/// Maps every scope the worker grants itself to the claims it
+/// releases. Other scopes are forwarded to Google.
+pub type ScopeRegistry = BTreeMap<String, Vec<String>>;
+
+/// Source: https://openid.net/specs/openid-connect-core-1_0.html#ScopeClaims
+const BUILT_IN_SCOPES: [(&str, &[&str]); 4] = [
+  ("email", &["email", "email_verified", "hd"]),
+  ("profile", &["name", "given_name", "family_name", "picture", "locale"]),
+  ("groups", &["groups"]),
+  ("roles", &["roles"])
+];
+
+/// Built in scopes added after `CUSTOM_SCOPES`. A custom scope can
+/// replace them, so a deployment that already defined one keeps it.
+const REPLACEABLE_SCOPES: [&str; 1] = ["roles"];
+
+/// JSON map from a custom scope to the claims it releases,
+/// e.g. `{"kubernetes": ["email", "groups"]}`
+struct CustomScopes(ScopeRegistry);
+impl FromStr for CustomScopes {
+  type Err = serde_json::Error;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    serde_json::from_str(s).map(CustomScopes)
+  }
+}
+
+/// Built in scopes plus the ones from the `CUSTOM_SCOPES` var
+pub fn scope_registry(env: &Env) -> Result<ScopeRegistry, HandlerError> {
+  let CustomScopes(custom) = get_var(
+    env,
+    CUSTOM_SCOPES,
+    CustomScopes(ScopeRegistry::new())
+  )?;
+
+  let mut registry = BUILT_IN_SCOPES.iter()
+    .map(|(scope, claims)| (
+      scope.to_string(),
+      claims.iter().map(|claim| claim.to_string()).collect()
+    ))
+    .collect::<ScopeRegistry>();
+
+  for (scope, claims) in custom {
+    let unknown_claim = claims.iter()
+      .any(|claim| !claim_names().any(|name| name == claim));
+
+    // can't redefine a built in scope, or "openid"
+    let built_in = registry.contains_key(&scope)
+      && !REPLACEABLE_SCOPES.contains(&scope.as_str());
+
+    if unknown_claim || scope == "openid" || built_in {
+      return Err(HandlerError::InvalidVar(CUSTOM_SCOPES))
+    }
+
+    registry.insert(scope, claims);
+  }
+
+  Ok(registry)
+}
@@ -0,0 +1,93 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{DateTime, Utc};
+use ed25519_dalek::pkcs8::KeypairBytes;
+use p256::pkcs8::{EncodePrivateKey, LineEnding};
+use rsa::{rand_core::OsRng, traits::PublicKeyParts, RsaPrivateKey};
+use serde_json::{Map, Value};
+
+use crate::{handler_error::HandlerError, oidc_token::new_token};
+
+use super::{KeyState, PublicJwk, SigningAlgorithm, SigningKey};
+
+const RSA_BITS: usize = 2048;
+
+/// Generate a new random key pair for `alg`
+pub fn generate_signing_key(
+  alg: SigningAlgorithm,
+  state: KeyState,
+  now: DateTime<Utc>
+) -> Result<SigningKey, HandlerError> {
+  let (private_key, mut params) = match alg {
+    SigningAlgorithm::RS256 => generate_rsa()?,
+    SigningAlgorithm::ES256 => generate_p256()?,
+    SigningAlgorithm::EdDSA => generate_ed25519()?
+  };
+
+  // Source: https://www.rfc-editor.org/rfc/rfc7517#section-4
+  params.insert("use".into(), "sig".into());
+  params.insert("alg".into(), alg.as_str().into());
+
+  Ok(SigningKey {
+    alg,
+    state,
+    state_since: now,
+    private_key,
+    public_key: PublicJwk {
+      kid: new_token::<12>(),
+      params
+    },
+    expires_at: None
+  })
+}
+
+/// Source: https://www.rfc-editor.org/rfc/rfc7518#section-6.3.1
+fn generate_rsa() -> Result<(String, Map<String, Value>), HandlerError> {
+  let private = RsaPrivateKey::new(&mut OsRng, RSA_BITS)
+    .map_err(|e| HandlerError::SigningKeyGeneration(e.to_string()))?;
+
+  let pem = private.to_pkcs8_pem(LineEnding::LF)
+    .map_err(|e| HandlerError::SigningKeyGeneration(e.to_string()))?;
+
+  let mut params = Map::new();
+  params.insert("kty".into(), "RSA".into());
+  params.insert("n".into(), URL_SAFE_NO_PAD.encode(private.n().to_bytes_be()).into());
+  params.insert("e".into(), URL_SAFE_NO_PAD.encode(private.e().to_bytes_be()).into());
+
+  Ok((pem.to_string(), params))
+}
+
+/// Source: https://www.rfc-editor.org/rfc/rfc7518#section-6.2.1
+fn generate_p256() -> Result<(String, Map<String, Value>), HandlerError> {
+  let private = p256::SecretKey::random(&mut OsRng);
+
+  let pem = private.to_pkcs8_pem(LineEnding::LF)
+    .map_err(|e| HandlerError::SigningKeyGeneration(e.to_string()))?;
+
+  // {"kty": "EC", "crv": "P-256", "x": ..., "y": ...}
+  let Value::Object(params) = serde_json::to_value(
+    private.public_key().to_jwk()
+  )? else {
+    return Err(HandlerError::SigningKeyGeneration("EC JWK is not an object".into()))
+  };
+
+  Ok((pem.to_string(), params))
+}
+
+/// Source: https://www.rfc-editor.org/rfc/rfc8037#section-2
+fn generate_ed25519() -> Result<(String, Map<String, Value>), HandlerError> {
+  let private = ed25519_dalek::SigningKey::generate(&mut OsRng);
+
+  // PKCS#8 v1 (no public key), which is the only version ring parses
+  let pem = KeypairBytes {
+    secret_key: private.to_bytes(),
+    public_key: None
+  }.to_pkcs8_pem(LineEnding::LF)
+    .map_err(|e| HandlerError::SigningKeyGeneration(e.to_string()))?;
+
+  let mut params = Map::new();
+  params.insert("kty".into(), "OKP".into());
+  params.insert("crv".into(), "Ed25519".into());
+  params.insert("x".into(), URL_SAFE_NO_PAD.encode(private.verifying_key().as_bytes()).into());
+
+  Ok((pem.to_string(), params))
+}
@@ -0,0 +1,291 @@
+mod generate;
+mod rotate;
+
+use std::{collections::BTreeSet, str::FromStr};
+
+use aes_gcm::{aead::{Aead, OsRng}, AeadCore, Aes256Gcm, KeyInit};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+use surrealdb_jsonwebtoken::{Algorithm, EncodingKey};
+use worker::{kv::KvStore, Env};
+
+use crate::{clients::{get_clients, ClientSecret}, consts::{get_optional_secret, get_var, JWK_PRIVATE, JWK_PUBLIC, KEY_ENCRYPTION_KEY, KEY_SIGNING_KEYS, KV_CACHE, SIGNING_ALGORITHM, SIGNING_KEY_ROTATION_DAYS}, handler_error::HandlerError, state::{cbor_deserialize, cbor_serialize, kv_get, kv_put}};
+
+pub use rotate::rotate_signing_keys;
+
+/// Algorithms the worker can generate keys for and sign with
+#[derive(Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug)]
+pub enum SigningAlgorithm {
+  RS256,
+  ES256,
+  /// Ed25519 only
+  EdDSA
+}
+impl SigningAlgorithm {
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      SigningAlgorithm::RS256 => "RS256",
+      SigningAlgorithm::ES256 => "ES256",
+      SigningAlgorithm::EdDSA => "EdDSA"
+    }
+  }
+
+  pub fn jwt_algorithm(&self) -> Algorithm {
+    match self {
+      SigningAlgorithm::RS256 => Algorithm::RS256,
+      SigningAlgorithm::ES256 => Algorithm::ES256,
+      SigningAlgorithm::EdDSA => Algorithm::EdDSA
+    }
+  }
+}
+impl FromStr for SigningAlgorithm {
+  type Err = ();
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "RS256" => Ok(SigningAlgorithm::RS256),
+      "ES256" => Ok(SigningAlgorithm::ES256),
+      "EdDSA" => Ok(SigningAlgorithm::EdDSA),
+      _ => Err(())
+    }
+  }
+}
+
+/// Lifecycle of a signing key. A rotation moves the `active` key to
+/// `retired` and the `next` key to `active`.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyState {
+  /// Published, but not used for signing yet. Gives clients time to
+  /// cache the key before it signs anything.
+  Next,
+  /// Published and used to sign new tokens. There is exactly one per
+  /// algorithm in use.
+  Active,
+  /// Published so tokens signed before the rotation still verify.
+  Retired
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PublicJwk {
+  pub kid: String,
+  #[serde(flatten)]
+  pub params: Map<String, Value>
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SigningKey {
+  pub alg: SigningAlgorithm,
+  pub state: KeyState,
+  /// When the key entered its current `state`
+  pub state_since: DateTime<Utc>,
+  /// PKCS#8 PEM encoded private key
+  pub private_key: String,
+  /// Public JWK served at `/jwks`. Its `kid` is set in the header of
+  /// every token signed with `private_key`.
+  pub public_key: PublicJwk,
+  /// Stop publishing the key after this time. Later than the
+  /// expiration of every token it signed.
+  pub expires_at: Option<DateTime<Utc>>
+}
+impl SigningKey {
+  pub fn kid(&self) -> &str {
+    &self.public_key.kid
+  }
+
+  pub fn encoding_key(&self) -> Result<EncodingKey, HandlerError> {
+    let pem = self.private_key.as_bytes();
+
+    match self.alg {
+      SigningAlgorithm::RS256 => EncodingKey::from_rsa_pem(pem),
+      SigningAlgorithm::ES256 => EncodingKey::from_ec_pem(pem),
+      SigningAlgorithm::EdDSA => EncodingKey::from_ed_pem(pem)
+    }.map_err(HandlerError::JwtIdToken)
+  }
+
+  fn is_expired(&self, now: DateTime<Utc>) -> bool {
+    self.expires_at.is_some_and(|expires_at| expires_at <= now)
+  }
+}
+
+/// Algorithm for clients without an `id_token_signed_response_alg`
+fn default_signing_algorithm(env: &Env) -> Result<SigningAlgorithm, HandlerError> {
+  get_var(env, SIGNING_ALGORITHM, SigningAlgorithm::RS256)
+}
+
+/// Algorithm to sign `client`'s ID tokens with
+pub fn client_signing_algorithm(
+  env: &Env,
+  client: &ClientSecret
+) -> Result<SigningAlgorithm, HandlerError> {
+  match client.id_token_signed_response_alg {
+    Some(alg) => Ok(alg),
+    None => default_signing_algorithm(env)
+  }
+}
+
+/// Every algorithm a registered client may need a key for
+pub fn signing_algorithms(env: &Env) -> Result<BTreeSet<SigningAlgorithm>, HandlerError> {
+  let mut algs = get_clients(env)?
+    .values()
+    .filter_map(|client| client.id_token_signed_response_alg)
+    .collect::<BTreeSet<_>>();
+
+  algs.insert(default_signing_algorithm(env)?);
+
+  Ok(algs)
+}
+
+/// How long a key spends in each `KeyState`. Must be longer than the
+/// lifetime of any token, so retired keys outlive the tokens they
+/// signed.
+fn rotation_period(env: &Env) -> Result<Duration, HandlerError> {
+  let days = get_var::<u32>(env, SIGNING_KEY_ROTATION_DAYS, 30)?;
+
+  // KV only accepts positive TTLs
+  if days == 0 {
+    return Err(HandlerError::InvalidVar(SIGNING_KEY_ROTATION_DAYS))
+  }
+
+  let period = Duration::days(days.into());
+
+  for client in get_clients(env)?.values() {
+    let lifetime = client.id_token_lifetime(env)?
+      .max(client.access_token_lifetime(env)?);
+
+    if lifetime >= period {
+      return Err(HandlerError::InvalidVar(SIGNING_KEY_ROTATION_DAYS))
+    }
+  }
+
+  Ok(period)
+}
+
+/// Load the stored signing keys. Keys are only generated by the
+/// scheduled rotation: concurrent requests generating keys would race,
+/// and tokens signed with a key that lost the race couldn't be
+/// verified. Until the rotation first runs, signing uses the imported
+/// key, or fails with `active_signing_key` without one.
+pub async fn get_signing_keys(env: &Env) -> Result<Vec<SigningKey>, HandlerError> {
+  let kv = KvStore::from_this(env, KV_CACHE)?;
+
+  let mut keys = match kv_get::<EncryptedSigningKeys>(&kv, KEY_SIGNING_KEYS).await? {
+    Some(stored) => stored.decrypt(env)?,
+    None => Vec::new()
+  };
+
+  import_signing_key(env, &mut keys, Utc::now())?;
+
+  Ok(keys)
+}
+
+/// Add the key of the `JWK_PRIVATE` and `JWK_PUBLIC` secrets, which
+/// deployments set before keys were generated, as the active RS256
+/// key while no keys are stored. Tokens it signed keep verifying
+/// after an upgrade, and it's rotated out like a generated key.
+fn import_signing_key(
+  env: &Env,
+  keys: &mut Vec<SigningKey>,
+  now: DateTime<Utc>
+) -> Result<(), HandlerError> {
+  // once stored, the key is only retired and dropped by rotations
+  if !keys.is_empty() {
+    return Ok(())
+  }
+
+  let (Ok(private_key), Ok(public_key)) = (
+    get_optional_secret(env, JWK_PRIVATE),
+    get_optional_secret(env, JWK_PUBLIC)
+  ) else {
+    return Ok(())
+  };
+
+  keys.push(SigningKey {
+    alg: SigningAlgorithm::RS256,
+    state: KeyState::Active,
+    state_since: now,
+    private_key,
+    public_key: serde_json::from_str(&public_key)?,
+    expires_at: None
+  });
+
+  Ok(())
+}
+
+/// The key new tokens are signed with
+pub fn active_signing_key(
+  keys: &[SigningKey],
+  alg: SigningAlgorithm
+) -> Result<&SigningKey, HandlerError> {
+  keys.iter()
+    .find(|key| key.alg == alg && key.state == KeyState::Active)
+    .ok_or(HandlerError::SigningKeys(
+      "no active signing key, wait for the scheduled rotation to generate it"
+    ))
+}
+
+/// All public keys a client may need to verify a token, including
+/// upcoming and recently retired ones.
+pub fn published_keys(keys: &[SigningKey]) -> impl Iterator<Item = &PublicJwk> {
+  let now = Utc::now();
+
+  keys.iter()
+    .filter(move |key| !key.is_expired(now))
+    .map(|key| &key.public_key)
+}
+
+// ---------- STORAGE ----------
+
+/// Signing keys are stored AES-256-GCM encrypted with a key derived
+/// from the `KEY_ENCRYPTION_KEY` secret.
+#[derive(Serialize, Deserialize)]
+struct EncryptedSigningKeys {
+  nonce: [u8; 12],
+  ciphertext: Vec<u8>
+}
+impl EncryptedSigningKeys {
+  fn encrypt(env: &Env, keys: &[SigningKey]) -> Result<Self, HandlerError> {
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+
+    let ciphertext = cipher(env)?
+      .encrypt(&nonce, cbor_serialize(&keys)?.as_slice())
+      .map_err(|_| HandlerError::SigningKeyEncryption)?;
+
+    Ok(EncryptedSigningKeys {
+      nonce: nonce.into(),
+      ciphertext
+    })
+  }
+
+  fn decrypt(&self, env: &Env) -> Result<Vec<SigningKey>, HandlerError> {
+    let plaintext = cipher(env)?
+      .decrypt(&self.nonce.into(), self.ciphertext.as_slice())
+      .map_err(|_| HandlerError::SigningKeyEncryption)?;
+
+    Ok(cbor_deserialize(&plaintext)?)
+  }
+}
+
+fn cipher(env: &Env) -> Result<Aes256Gcm, HandlerError> {
+  let key = Sha256::digest(
+    get_optional_secret(env, KEY_ENCRYPTION_KEY)?.as_bytes()
+  );
+
+  Ok(Aes256Gcm::new(&key))
+}
+
+async fn store_signing_keys(
+  env: &Env,
+  keys: &[SigningKey]
+) -> Result<(), HandlerError> {
+  kv_put(
+    &KvStore::from_this(env, KV_CACHE)?,
+    KEY_SIGNING_KEYS,
+    &EncryptedSigningKeys::encrypt(env, keys)?,
+    // If rotations stop running, let the keys lapse rather than
+    // signing with them forever. The next rotation regenerates them.
+    rotation_period(env)? * 3
+  ).await
+}
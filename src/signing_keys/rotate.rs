@@ -0,0 +1,91 @@
+use chrono::{DateTime, Duration, Utc};
+use worker::{kv::KvStore, Env};
+
+use crate::{consts::{KEY_SIGNING_KEYS, KV_CACHE}, handler_error::HandlerError, state::kv_get};
+
+use super::{generate::generate_signing_key, import_signing_key, rotation_period, signing_algorithms, store_signing_keys, EncryptedSigningKeys, KeyState, SigningAlgorithm, SigningKey};
+
+/// Advance every stored key through its lifecycle and store the
+/// result. Runs on the Cron Trigger, so it should be cheap when
+/// nothing is due.
+///
+/// Each key is `next` for one rotation period so clients can cache
+/// it, `active` for one period, then `retired` for one more period
+/// so tokens it signed still verify. `force` promotes the `next` keys
+/// right away, e.g. after an active key leaked.
+pub async fn rotate_signing_keys(
+  env: &Env,
+  force: bool
+) -> Result<Vec<SigningKey>, HandlerError> {
+  let kv = KvStore::from_this(env, KV_CACHE)?;
+
+  let mut keys = match kv_get::<EncryptedSigningKeys>(&kv, KEY_SIGNING_KEYS).await? {
+    Some(stored) => stored.decrypt(env)?,
+    None => Vec::new()
+  };
+
+  let now = Utc::now();
+  let period = rotation_period(env)?;
+  let algs = signing_algorithms(env)?;
+
+  keys.retain(|key| !key.is_expired(now));
+
+  // stored on the first run, instead of generating an RS256 key
+  import_signing_key(env, &mut keys, now)?;
+
+  // keys for an algorithm no client uses anymore
+  keys.iter_mut()
+    .filter(|key| !algs.contains(&key.alg) && key.state != KeyState::Retired)
+    .for_each(|key| retire(key, now, period));
+
+  for alg in algs {
+    rotate_algorithm(&mut keys, alg, now, period, force)?;
+  }
+
+  store_signing_keys(env, &keys).await?;
+
+  Ok(keys)
+}
+
+fn rotate_algorithm(
+  keys: &mut Vec<SigningKey>,
+  alg: SigningAlgorithm,
+  now: DateTime<Utc>,
+  period: Duration,
+  force: bool
+) -> Result<(), HandlerError> {
+  let find = |keys: &[SigningKey], state| keys.iter()
+    .position(|key| key.alg == alg && key.state == state);
+
+  match (find(keys, KeyState::Active), find(keys, KeyState::Next)) {
+    // first run
+    (None, None) => keys.push(
+      generate_signing_key(alg, KeyState::Active, now)?
+    ),
+    (None, Some(next)) => promote(&mut keys[next], now),
+    (Some(active), Some(next))
+      if force || keys[active].state_since + period <= now =>
+    {
+      retire(&mut keys[active], now, period);
+      promote(&mut keys[next], now);
+    },
+    _ => ()
+  }
+
+  if find(keys, KeyState::Next).is_none() {
+    keys.push(generate_signing_key(alg, KeyState::Next, now)?);
+  }
+
+  Ok(())
+}
+
+fn promote(key: &mut SigningKey, now: DateTime<Utc>) {
+  key.state = KeyState::Active;
+  key.state_since = now;
+}
+
+fn retire(key: &mut SigningKey, now: DateTime<Utc>, period: Duration) {
+  key.state = KeyState::Retired;
+  key.state_since = now;
+  key.expires_at = Some(now + period);
+}
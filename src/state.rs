@@ -1,75 +1,123 @@
 use std::io;
 
-use chrono::Duration;
-use openidconnect::{core::{CoreJsonWebKey, CoreJsonWebKeyType, CoreJsonWebKeyUse, CoreJwsSigningAlgorithm, CoreProviderMetadata}, AuthorizationCode, JsonWebKeySet, Nonce, RefreshToken};
+use chrono::{DateTime, Duration, Utc};
+use openidconnect::{core::{CoreJsonWebKey, CoreJsonWebKeyType, CoreJsonWebKeyUse, CoreJwsSigningAlgorithm, CoreProviderMetadata}, JsonWebKeySet, Nonce, RefreshToken};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use url::Url;
 use worker::{kv::KvStore, Env};
 
-use crate::{consts::KV_REFRESH_TOKEN_STATE, handler_error::HandlerError};
+use crate::{claims::ClaimsRequest, consts::KV_REFRESH_TOKEN_STATE, google::GoogleIdToken, handler_error::HandlerError};
 
 #[derive(Serialize, Deserialize)]
-pub struct GenericAuthorizeState<S, U, N> {
+pub struct GenericAuthorizeState<S, U, N, R> {
   pub client_id: S,
   pub client_redirect: U,
   pub client_state: S,
   pub client_nonce: S,
   pub google_nonce: N,
-  pub groups_scope: bool
+  /// Scopes from `scope::scope_registry` granted to the client
+  #[serde(default)]
+  pub scopes: Vec<S>,
+  pub claims: Option<R>,
+  /// Source: https://openid.net/specs/openid-connect-core-1_0.html#AuthRequest (acr_values)
+  #[serde(default)]
+  pub acr_values: Vec<S>,
+  /// Source: https://www.rfc-editor.org/rfc/rfc8707#section-2.1
+  #[serde(default)]
+  pub resources: Vec<S>,
+  pub browser_binding: S
 }
 /// Struct for storing both the client's authorization state
 /// and Google's authorization state between worker requests.
 pub type AuthorizeState = GenericAuthorizeState<
   String,
   Url,
-  Nonce
+  Nonce,
+  ClaimsRequest
 >;
 /// borrowed version of AuthorizeState to avoid clones
 pub type AuthorizeStateRef<'a> = GenericAuthorizeState<
   &'a str,
   &'a Url,
-  &'a Nonce
+  &'a Nonce,
+  &'a ClaimsRequest
 >;
 
 #[derive(Serialize, Deserialize)]
-pub struct GenericCommonTokenState<S, N> {
+pub struct GenericCommonTokenState<S, N, R> {
   pub client_id: S,
   pub client_nonce: S,
   pub google_nonce: N,
-  pub groups_scope: bool
+  /// Scopes from `scope::scope_registry` granted to the client
+  #[serde(default)]
+  pub scopes: Vec<S>,
+  /// States stored before `scopes` only recorded whether the groups
+  /// scope was granted. See `upgrade_legacy_scopes`.
+  #[serde(default, skip_serializing)]
+  pub groups_scope: bool,
+  /// `None` for states stored before the `claims` parameter was
+  /// supported
+  pub claims: Option<R>,
+  /// When the user last authenticated with Google
+  pub auth_time: Option<DateTime<Utc>>,
+  /// How the user last authenticated, from `authentication::authenticate`
+  #[serde(default)]
+  pub amr: Vec<S>,
+  pub acr: Option<S>,
+  /// Checked again on every refresh
+  #[serde(default)]
+  pub acr_values: Vec<S>,
+  /// Granted at `/authorize`. Access tokens are issued for some or all
+  /// of them.
+  #[serde(default)]
+  pub resources: Vec<S>
 }
 /// Struct for storing both the client's session state
 /// and Google's session state between worker requests.
 pub type CommonTokenState = GenericCommonTokenState<
   String,
-  Nonce
+  Nonce,
+  ClaimsRequest
 >;
+impl CommonTokenState {
+  /// Grant the groups scope to states stored before `scopes`, so
+  /// their refresh tokens keep returning groups
+  pub fn upgrade_legacy_scopes(&mut self) {
+    if self.groups_scope && !self.scopes.iter().any(|scope| scope == "groups") {
+      self.scopes.push("groups".into());
+    }
+
+    self.groups_scope = false;
+  }
+}
 /// borrowed version of CommonTokenState to avoid clones
 pub type CommonTokenStateRef<'a> = GenericCommonTokenState<
   &'a str,
-  &'a Nonce
+  &'a Nonce,
+  &'a ClaimsRequest
 >;
 
 // ---------- ACCESS TOKEN STATE ----------
 
 #[derive(Serialize, Deserialize)]
-pub struct GenericAccessTokenState<C, U, A> {
+pub struct GenericAccessTokenState<C, U, G> {
   pub common: C,
   pub client_redirect: U,
-  pub google_code: A
+  /// Google's code is exchanged in `/callback`
+  pub google_token: G
 }
 /// Struct for storing both the client and Google's
 /// access token state between worker requests.
 pub type AccessTokenState = GenericAccessTokenState<
   CommonTokenState,
   Url,
-  AuthorizationCode
+  GoogleIdToken
 >;
 /// borrowed version of AccessTokenState to avoid clones
 pub type AccessTokenStateRef<'a> = GenericAccessTokenState<
   CommonTokenStateRef<'a>,
   &'a Url,
-  &'a AuthorizationCode
+  &'a GoogleIdToken
 >;
 
 // ---------- REFRESH TOKEN STATE ----------
@@ -163,12 +211,12 @@ pub async fn kv_put<S: Serialize>(
 
 // ---------- CBOR ----------
 
-fn cbor_serialize<T: Serialize>(data: &T) -> Result<Vec<u8>, ciborium::ser::Error<io::Error>> {
+pub fn cbor_serialize<T: Serialize>(data: &T) -> Result<Vec<u8>, ciborium::ser::Error<io::Error>> {
   let mut buffer = Vec::new();
   ciborium::into_writer(data, &mut buffer)?;
   Ok(buffer)
 }
 
-fn cbor_deserialize<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, ciborium::de::Error<io::Error>> {
+pub fn cbor_deserialize<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, ciborium::de::Error<io::Error>> {
   ciborium::from_reader(bytes)
 }
\ No newline at end of file
@@ -0,0 +1,130 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::Duration;
+use openidconnect::SubjectIdentifier;
+use reqwest::Client;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use url::Url;
+use worker::{kv::KvStore, Env};
+
+use crate::{clients::ClientSecret, consts::{get_optional_secret, KEY_SECTOR_IDENTIFIER, KV_CACHE, PAIRWISE_SUBJECT_SALT}, handler_error::HandlerError, state::{kv_get, kv_put}};
+
+/// Source: https://openid.net/specs/openid-connect-core-1_0.html#SubjectIDTypes
+#[derive(Deserialize, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SubjectType {
+  /// Google's `sub`, the same for every client
+  #[default]
+  Public,
+  /// Different for every sector, so clients can't correlate users
+  Pairwise
+}
+impl SubjectType {
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      SubjectType::Public => "public",
+      SubjectType::Pairwise => "pairwise"
+    }
+  }
+}
+
+/// The `sub` claim `client` sees for the Google user `google_subject`
+pub async fn client_subject(
+  env: &Env,
+  client: &ClientSecret,
+  google_subject: &SubjectIdentifier
+) -> Result<SubjectIdentifier, HandlerError> {
+  match client.subject_type {
+    SubjectType::Public => Ok(google_subject.clone()),
+    // Source: https://openid.net/specs/openid-connect-core-1_0.html#PairwiseAlg
+    SubjectType::Pairwise => {
+      let mut hasher = Sha256::new();
+
+      // Length prefix every input, so different inputs can't
+      // concatenate to the same bytes
+      for input in [
+        sector_identifier(env, client).await?,
+        google_subject.as_str(),
+        &get_optional_secret(env, PAIRWISE_SUBJECT_SALT)?
+      ] {
+        hasher.update((input.len() as u64).to_be_bytes());
+        hasher.update(input);
+      }
+
+      Ok(SubjectIdentifier::new(
+        URL_SAFE_NO_PAD.encode(hasher.finalize())
+      ))
+    }
+  }
+}
+
+/// Host of the `sector_identifier_uri`, or of the redirect_uris if they
+/// all share one.
+/// Source: https://openid.net/specs/openid-connect-core-1_0.html#PairwiseAlg
+async fn sector_identifier<'a>(
+  env: &Env,
+  client: &'a ClientSecret
+) -> Result<&'a str, HandlerError> {
+  if let Some(uri) = &client.sector_identifier_uri {
+    verify_sector_identifier_uri(env, client, uri).await?;
+
+    return uri.host_str()
+      .ok_or(HandlerError::SectorIdentifier("sector_identifier_uri has no host"))
+  }
+
+  let mut hosts = client.redirect_uris
+    .iter()
+    .map(|uri| uri.host_str());
+
+  match hosts.next() {
+    Some(Some(host)) if hosts.all(|other| other == Some(host)) => Ok(host),
+    _ => Err(HandlerError::SectorIdentifier(
+      "redirect_uris don't share a single host, so a sector_identifier_uri is required"
+    ))
+  }
+}
+
+/// The `sector_identifier_uri` has to be an https URL of a JSON array
+/// that includes every redirect_uri of the client, so a client can
+/// only share a sector with hosts that agreed to it.
+/// Source: https://openid.net/specs/openid-connect-registration-1_0.html#SectorIdentifierValidation
+async fn verify_sector_identifier_uri(
+  env: &Env,
+  client: &ClientSecret,
+  uri: &Url
+) -> Result<(), HandlerError> {
+  if uri.scheme() != "https" {
+    return Err(HandlerError::SectorIdentifier("sector_identifier_uri must use https"))
+  }
+
+  let kv = KvStore::from_this(env, KV_CACHE)?;
+  let key = format!("{KEY_SECTOR_IDENTIFIER}/{uri}");
+
+  let redirect_uris = match kv_get::<Vec<Url>>(&kv, &key).await? {
+    Some(cached) => cached,
+    None => {
+      let fetched = Client::new()
+        .get(uri.clone())
+        .send()
+        .await
+        .map_err(HandlerError::SectorIdentifierUri)?
+        .error_for_status()
+        .map_err(HandlerError::SectorIdentifierUri)?
+        .json::<Vec<Url>>()
+        .await
+        .map_err(HandlerError::SectorIdentifierUri)?;
+
+      kv_put(&kv, &key, &fetched, Duration::hours(1)).await?;
+
+      fetched
+    }
+  };
+
+  if !client.redirect_uris.iter().all(|uri| redirect_uris.contains(uri)) {
+    return Err(HandlerError::SectorIdentifier(
+      "redirect_uris aren't all listed at the sector_identifier_uri"
+    ))
+  }
+
+  Ok(())
+}
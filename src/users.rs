@@ -0,0 +1,101 @@
+use itertools::Itertools;
+use reqwest::Client;
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::{Map, Value};
+use url::Url;
+use worker::{console_error, Env};
+
+use crate::{clients::ClientSecret, groups::get_access_token, handler_error::HandlerError};
+
+const USER_SCOPE: &str = "https://www.googleapis.com/auth/admin.directory.user.readonly";
+
+// Spec: https://developers.google.com/admin-sdk/directory/reference/rest/v1/users#User
+#[derive(Deserialize)]
+struct DirectoryUser {
+  #[serde(rename = "isEnforcedIn2Sv", default)]
+  is_enforced_in_2sv: bool
+}
+
+/// Whether the workspace requires `user_email` to use 2-Step
+/// Verification. Says nothing about how any one login happened.
+pub async fn is_enforced_in_2sv(
+  env: &Env,
+  user_email: &str
+) -> Result<bool, HandlerError> {
+  let DirectoryUser { is_enforced_in_2sv } = get_user(env, user_email, &[]).await?;
+
+  Ok(is_enforced_in_2sv)
+}
+
+/// Values of the client's `directory_claims`, read from the user's
+/// Directory API resource. Fields the user doesn't have are left out,
+/// and so is every claim while the Directory API is failing.
+pub async fn directory_claims(
+  env: &Env,
+  client: &ClientSecret,
+  user_email: &str
+) -> Map<String, Value> {
+  if client.directory_claims.is_empty() {
+    return Map::new()
+  }
+
+  // Custom schema fields are only returned for the schemas named in
+  // `customFieldMask`
+  let schemas = client.directory_claims.values()
+    .filter_map(|pointer| pointer.strip_prefix("/customSchemas/"))
+    .filter_map(|rest| rest.split('/').next())
+    .unique()
+    .join(",");
+
+  let params = if schemas.is_empty() {
+    Vec::new()
+  } else {
+    vec![("projection", "custom"), ("customFieldMask", schemas.as_str())]
+  };
+
+  let user = match get_user::<Value>(env, user_email, &params).await {
+    Ok(user) => user,
+    Err(e) => {
+      // Nonfatal, but the user's tokens are missing the claims
+      // TODO: telemetry?
+      console_error!(r#"Failed to get the directory claims of "{user_email}": {e}"#);
+      return Map::new()
+    }
+  };
+
+  client.directory_claims.iter()
+    .filter_map(|(claim, pointer)| Some((claim.clone(), user.pointer(pointer)?.clone())))
+    .collect()
+}
+
+/// Get the user's Directory API resource with the service account
+async fn get_user<T: DeserializeOwned>(
+  env: &Env,
+  user_email: &str,
+  params: &[(&str, &str)]
+) -> Result<T, HandlerError> {
+  let client = Client::new();
+
+  let access_token = get_access_token(env, &client, USER_SCOPE).await?;
+
+  // Source: https://developers.google.com/admin-sdk/directory/reference/rest/v1/users/get
+  let mut url = Url::parse("https://admin.googleapis.com/admin/directory/v1/users")
+    // valid static url
+    .unwrap();
+  url.path_segments_mut()
+    // https urls can be a base
+    .unwrap()
+    .push(user_email);
+  url.query_pairs_mut().extend_pairs(params);
+
+  client.get(url)
+    .bearer_auth(&access_token)
+    .send()
+    .await
+    .map_err(HandlerError::UsersAdminApi)?
+    .error_for_status()
+    .map_err(HandlerError::UsersAdminApi)?
+    .json()
+    .await
+    .map_err(HandlerError::UsersAdminApi)
+}